@@ -0,0 +1,3 @@
+#!/bin/bash
+cp /data/binaries/audit_binary .
+./audit_binary
\ No newline at end of file
@@ -0,0 +1,225 @@
+use std::str::FromStr;
+
+use axum::{extract::Path, Json};
+use chrono::Utc;
+use common::{
+    context::{Context, ContextExtractor, MutationContext},
+    entity::{
+        audit::{Audit, AuditPublic, AuditStatus},
+        audit_request::{AuditRequest, AuditRequestPublic, Offer, Party},
+        project::Project,
+        Entity,
+    },
+    error::{ServiceError, ServiceResponse},
+    repository::{ReadRepositoryTrait, RepositoryTrait},
+};
+use mongodb::bson::{doc, oid::ObjectId, to_bson};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub status: AuditStatus,
+    /// Required when submitting the report.
+    pub report_link: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewAuditRequest {
+    pub auditor_id: ObjectId,
+    pub customer_id: ObjectId,
+    pub project_id: ObjectId,
+    #[serde(default)]
+    pub avatar: String,
+    #[serde(flatten)]
+    pub offer: Offer,
+}
+
+fn changed_meanwhile() -> ServiceError {
+    ServiceError::Conflict("The request changed meanwhile, reload it".to_string())
+}
+
+/// Loads the request `id` along with the side the caller is on.
+async fn find_request(id: &str, context: &Context) -> Result<(AuditRequest, Party), ServiceError> {
+    let id = ObjectId::from_str(id)?;
+    let user = context.caller()?;
+    let Some(request) = context
+        .repository::<AuditRequest>()?
+        .find(&id, context)
+        .await?
+    else {
+        return Err(ServiceError::NotFound(
+            "Audit request not found".to_string(),
+        ));
+    };
+    let Some(party) = request.party_of(user) else {
+        return Err(ServiceError::Forbidden(
+            "Only the customer and the auditor can negotiate the request".to_string(),
+        ));
+    };
+    Ok((request, party))
+}
+
+/// Opens the negotiation with the first offer of the caller.
+pub async fn create_request(
+    ContextExtractor(context): ContextExtractor,
+    Json(new_request): Json<NewAuditRequest>,
+) -> ServiceResponse<AuditRequestPublic> {
+    let user = context.caller()?;
+    let creator = if user == new_request.customer_id {
+        Party::Customer
+    } else if user == new_request.auditor_id {
+        Party::Auditor
+    } else {
+        return Err(ServiceError::Forbidden(
+            "Audit requests are opened by their customer or auditor".to_string(),
+        ));
+    };
+    if new_request.customer_id == new_request.auditor_id {
+        return Err(ServiceError::BadRequest(
+            "Customer and auditor must differ".to_string(),
+        ));
+    }
+
+    // Requests make their parties counterparts, so the customer must own the project.
+    let project = context
+        .repository::<Project>()?
+        .find(&new_request.project_id, &context.privileged())
+        .await?;
+    if project.map(|project| project.customer_id) != Some(new_request.customer_id) {
+        return Err(ServiceError::NotFound(
+            "The customer has no such project".to_string(),
+        ));
+    }
+
+    let repository = context.repository::<AuditRequest>()?;
+    let existing = repository
+        .find_by_doc(
+            doc! {
+                "auditor_id": new_request.auditor_id,
+                "customer_id": new_request.customer_id,
+                "project_id": new_request.project_id,
+            },
+            &context,
+        )
+        .await?;
+    if existing.is_some() {
+        return Err(ServiceError::Conflict(
+            "The project already has a request with this auditor".to_string(),
+        ));
+    }
+
+    let mut request = AuditRequest::new(
+        new_request.auditor_id,
+        new_request.customer_id,
+        new_request.project_id,
+        creator,
+        new_request.offer,
+        Utc::now().timestamp(),
+    );
+    request.avatar = new_request.avatar;
+    repository.insert(&request, &context).await?;
+
+    Ok(Json(request.to_public(&mut MutationContext::new(&context))))
+}
+
+/// Answers the last offer of the other party with a counter-offer.
+pub async fn offer(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+    Json(offer): Json<Offer>,
+) -> ServiceResponse<AuditRequestPublic> {
+    let (mut request, party) = find_request(&id, &context).await?;
+    let revision = request.revision();
+    request.counter(party, offer, Utc::now().timestamp())?;
+
+    let Some(request) = context
+        .repository::<AuditRequest>()?
+        .update_if(request.id, revision, &request, &context)
+        .await?
+    else {
+        return Err(changed_meanwhile());
+    };
+
+    Ok(Json(request.to_public(&mut MutationContext::new(&context))))
+}
+
+/// Accepts the last offer of the other party, turning the request into an audit.
+///
+/// The request is first marked accepted, which only one of concurrent offers and accepts
+/// can do. Then the audit, sharing the id of the request, replaces it. Accepting a request
+/// left marked by an interrupted conversion finishes that conversion.
+pub async fn accept(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+) -> ServiceResponse<AuditPublic> {
+    let (mut request, party) = find_request(&id, &context).await?;
+    let requests = context.repository::<AuditRequest>()?;
+    let audits = context.repository::<Audit>()?;
+    let now = Utc::now().timestamp();
+
+    let audit = if request.accepted {
+        request.to_audit(now)?
+    } else {
+        let revision = request.revision();
+        let audit = request.accept(party, now)?;
+        if requests
+            .update_if(request.id, revision, &request, &context)
+            .await?
+            .is_none()
+        {
+            return Err(changed_meanwhile());
+        }
+        audit
+    };
+
+    let audit = match audits.find(&audit.id, &context).await? {
+        Some(stored) => stored,
+        None => {
+            audits.insert(&audit, &context).await?;
+            audit
+        }
+    };
+    requests.delete(request.id, &context).await?;
+
+    Ok(Json(audit.to_public(&mut MutationContext::new(&context))))
+}
+
+/// Moves the audit along its lifecycle, see `AuditStatus::TRANSITIONS`.
+pub async fn change_status(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+    Json(change): Json<StatusChange>,
+) -> ServiceResponse<AuditPublic> {
+    let id = ObjectId::from_str(&id)?;
+    let user = context.caller()?;
+    let repository = context.repository::<Audit>()?;
+    let Some(mut audit) = repository.find(&id, &context).await? else {
+        return Err(ServiceError::NotFound("Audit not found".to_string()));
+    };
+
+    // Only written while no other transition landed since the read, so none is lost.
+    let condition = doc! {
+        "status": to_bson(&audit.status)?,
+        "history": { "$size": audit.history.len() as i64 },
+    };
+    audit.transition(change.status, user, Utc::now().timestamp())?;
+    if change.status == AuditStatus::ReportSubmitted {
+        let Some(report_link) = change.report_link else {
+            return Err(ServiceError::BadRequest(
+                "Report link is required to submit the report".to_string(),
+            ));
+        };
+        audit.report_link = Some(report_link);
+    }
+
+    let Some(audit) = repository
+        .update_if(id, condition, &audit, &context)
+        .await?
+    else {
+        return Err(ServiceError::Conflict(
+            "The audit changed meanwhile, reload it".to_string(),
+        ));
+    };
+
+    Ok(Json(audit.to_public(&mut MutationContext::new(&context))))
+}
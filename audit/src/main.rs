@@ -0,0 +1,47 @@
+use std::{sync::Arc, env, net::SocketAddr};
+
+use axum::{routing::{get, post}, Router};
+use common::{auth::keys, context::ServiceState, entity::{audit::Audit, audit_request::AuditRequest, project::Project}, resilience, repository::{Repository, memory::InMemoryRepository, mongo::MongoRepository, http_repository::{HttpRepositoryClient, Registrable}}, services::REGISTRY};
+use audit::handlers;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+    .with_max_level(tracing::Level::DEBUG)
+    .init();
+
+    let mut state = ServiceState::new("audit".to_string());
+    match env::var("MONGOURI") {
+        Ok(mongo_uri) => {
+            state.insert(Repository(Arc::new(MongoRepository::<AuditRequest>::new(&mongo_uri, "audit", "audit_request").await)));
+            state.insert(Repository(Arc::new(MongoRepository::<Audit>::new(&mongo_uri, "audit", "audit").await)));
+        }
+        Err(_) => {
+            tracing::warn!("MONGOURI is not set, audits are kept in memory");
+            state.insert(Repository(Arc::new(InMemoryRepository::<AuditRequest>::new())));
+            state.insert(Repository(Arc::new(InMemoryRepository::<Audit>::new())));
+        }
+    }
+
+    state.insert(Repository(Arc::new(HttpRepositoryClient::<Project>::from_registry(&REGISTRY, "project").unwrap())));
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3004));
+
+    let router = Router::new()
+        .register::<AuditRequest>()
+        .register::<Audit>()
+        .route("/api/audit_request/create", post(handlers::create_request))
+        .route("/api/audit_request/:id/offer", post(handlers::offer))
+        .route("/api/audit_request/:id/accept", post(handlers::accept))
+        .route("/api/audit/:id/status", post(handlers::change_status))
+        .route("/api/audit/jwks", get(keys::jwks))
+        .route("/metrics", get(resilience::metrics))
+        .with_state(Arc::new(state));
+
+    tracing::info!("routs {:?}", router);
+
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await
+        .unwrap();
+}
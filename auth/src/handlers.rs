@@ -0,0 +1,193 @@
+use axum::Json;
+use common::{
+    auth::{Auth, TokenPair},
+    context::ContextExtractor,
+    entity::{Private, Unique},
+    error::{ServiceError, ServiceResponse},
+    repository::{ReadRepositoryTrait, RepositoryTrait},
+};
+use mongodb::bson::{doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    password::{hash_password, verify_password},
+    Login,
+};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Creates a login with an Argon2 hashed password and signs the new user in.
+pub async fn register(
+    ContextExtractor(context): ContextExtractor,
+    Json(credentials): Json<Credentials>,
+) -> ServiceResponse<TokenPair> {
+    if credentials.login.trim().is_empty() {
+        return Err(ServiceError::BadRequest(
+            "Login must not be empty".to_string(),
+        ));
+    }
+    if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ServiceError::BadRequest(format!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+
+    let repository = context.repository::<Login>()?;
+
+    // Logins are scoped to their user, so the lookup must see all of them.
+    let existing = repository
+        .find_by_doc(doc! { "login": &credentials.login }, &context.privileged())
+        .await?;
+    if existing.is_some() {
+        return Err(ServiceError::Conflict("Login is already taken".to_string()));
+    }
+
+    let hashed = hash_password(credentials.password).await?;
+
+    let login = Login {
+        id: ObjectId::new(),
+        login: Unique::new(credentials.login),
+        password: Private::new(hashed.hash),
+        password_salt: Private::new(hashed.salt),
+    };
+
+    repository.insert(&login, &context).await?;
+
+    Ok(Json(Auth::User(login.id).to_token_pair()?))
+}
+
+/// Verifies the credentials and returns a fresh token pair.
+pub async fn login(
+    ContextExtractor(context): ContextExtractor,
+    Json(credentials): Json<Credentials>,
+) -> ServiceResponse<TokenPair> {
+    let invalid = || ServiceError::Unauthorized("Invalid login or password".to_string());
+
+    let repository = context.repository::<Login>()?;
+
+    let login = repository
+        .find_by_doc(doc! { "login": &credentials.login }, &context.privileged())
+        .await?;
+
+    // Unknown logins are still hashed, so timing doesn't tell which logins exist.
+    let hash = login.as_ref().map(|login| login.password.value.clone());
+    let verified = verify_password(credentials.password, hash).await?;
+    match login {
+        Some(login) if verified => Ok(Json(Auth::User(login.id).to_token_pair()?)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Exchanges a valid refresh token for a new access and refresh token pair.
+pub async fn refresh(Json(request): Json<RefreshRequest>) -> ServiceResponse<TokenPair> {
+    let auth = Auth::from_refresh_token(&request.refresh_token)?;
+    Ok(Json(auth.to_token_pair()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::{
+        context::{Context, HandlerContext, ServiceState},
+        error::ErrorCode,
+        repository::{memory::InMemoryRepository, Repository},
+    };
+
+    use super::*;
+
+    fn state() -> Arc<ServiceState> {
+        let mut state = ServiceState::new("test".to_string());
+        state.insert(Repository::<Login>(Arc::new(InMemoryRepository::new())));
+        Arc::new(state)
+    }
+
+    fn anonymous(state: &Arc<ServiceState>) -> ContextExtractor {
+        ContextExtractor(Context(
+            Arc::clone(state),
+            HandlerContext {
+                user_auth: None,
+                on_behalf_of: None,
+                forwarded_token: None,
+            },
+        ))
+    }
+
+    fn credentials(login: &str, password: &str) -> Json<Credentials> {
+        Json(Credentials {
+            login: login.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn registers_and_logs_in() {
+        let state = state();
+        let registered = register(anonymous(&state), credentials("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_eq!(registered.token_type, "Bearer");
+
+        let ContextExtractor(context) = anonymous(&state);
+        let stored = context
+            .repository::<Login>()
+            .unwrap()
+            .find_by_doc(doc! { "login": "alice" }, &context.privileged())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.password.value.starts_with("$argon2id$"));
+
+        let logged_in = login(anonymous(&state), credentials("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_ne!(logged_in.refresh_token, "");
+    }
+
+    #[tokio::test]
+    async fn rejects_taken_logins() {
+        let state = state();
+        assert!(
+            register(anonymous(&state), credentials("alice", "correct horse"))
+                .await
+                .is_ok()
+        );
+
+        let err = register(anonymous(&state), credentials("alice", "battery staple"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_passwords_like_unknown_logins() {
+        let state = state();
+        assert!(
+            register(anonymous(&state), credentials("alice", "correct horse"))
+                .await
+                .is_ok()
+        );
+
+        let wrong_password = login(anonymous(&state), credentials("alice", "battery staple"))
+            .await
+            .unwrap_err();
+        let unknown_login = login(anonymous(&state), credentials("bob", "correct horse"))
+            .await
+            .unwrap_err();
+        assert_eq!(wrong_password.code(), ErrorCode::Unauthorized);
+        assert_eq!(wrong_password.to_string(), unknown_login.to_string());
+        assert_eq!(unknown_login.code(), ErrorCode::Unauthorized);
+    }
+}
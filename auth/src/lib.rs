@@ -1,55 +1,36 @@
-use axum::async_trait;
+pub mod handlers;
+pub mod password;
+
 use mongodb::bson::oid::ObjectId;
 
 use common::repository::Method;
 use common::{
-    context::MutationContext,
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
     entity::{Entity, Private, Unique},
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "login", public = "LoginPublic")]
 pub struct Login {
     #[serde(rename = "_id")]
+    #[entity(owner)]
     pub id: ObjectId,
     pub login: Unique<String>,
     pub password: Private<String>,
     pub password_salt: Private<String>,
 }
 
-pub struct LoginPublic {
-    pub id: String,
-    pub login: String,
-}
-
-#[async_trait]
-impl Entity<Login> for Login {
-    type PublicEntity = LoginPublic;
-
-    const NAME: &'static str = "login";
-
-    fn to_public(&self, context: &mut MutationContext) -> Self::PublicEntity {
-        LoginPublic {
-            id: <ObjectId as Entity<Login>>::to_public(&self.id, context),
-            login: self.login.value.clone(),
+impl Policy for Login {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            (Some(Auth::User(_)), Method::Find | Method::FindByDoc) => Access::Owner,
+            // Logins are created through `/api/auth/register`.
+            _ => Access::Deny,
         }
     }
-
-    async fn before_execution(
-        &self,
-        context: &mut MutationContext,
-        method: Method,
-    ) -> anyhow::Result<bool> {
-        let future = <ObjectId as Entity<Login>>::before_execution(&self.id, context, method);
-        future.await
-    }
-
-    async fn after_execution(
-        &self,
-        context: &mut MutationContext,
-        method: Method,
-    ) -> anyhow::Result<bool> {
-        let future = <ObjectId as Entity<Login>>::after_execution(&self.id, context, method);
-        future.await
-    }
 }
@@ -1,8 +1,8 @@
 use std::{sync::Arc, env, net::SocketAddr};
 
-use auth::Login;
-use axum::Router;
-use common::{context::ServiceState, repository::{Repository, mongo::MongoRepository, http_repository::Registrable}};
+use auth::{handlers, Login};
+use axum::{routing::{get, post}, Router};
+use common::{auth::keys::{self, KEYS}, context::ServiceState, resilience, repository::{Repository, memory::InMemoryRepository, mongo::MongoRepository, http_repository::Registrable}};
 
 #[tokio::main]
 async fn main() {
@@ -10,15 +10,28 @@ async fn main() {
     .with_max_level(tracing::Level::DEBUG)
     .init();
 
-    let mongo_uri = env::var("MONGOURI").unwrap();
-
     let mut state = ServiceState::new("auth".to_string());
-    state.insert(Repository(Arc::new(MongoRepository::<Login>::new(&mongo_uri, "auth", "auth").await)));
+    if KEYS.signing_key().is_none() {
+        panic!("JWT_SIGNING_KEY must be set for the auth service");
+    }
+
+    match env::var("MONGOURI") {
+        Ok(mongo_uri) => state.insert(Repository(Arc::new(MongoRepository::<Login>::new(&mongo_uri, "auth", "auth").await))),
+        Err(_) => {
+            tracing::warn!("MONGOURI is not set, logins are kept in memory");
+            state.insert(Repository(Arc::new(InMemoryRepository::<Login>::new())));
+        }
+    }
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
 
     let router = Router::new()
         .register::<Login>()
+        .route("/api/auth/register", post(handlers::register))
+        .route("/api/auth/login", post(handlers::login))
+        .route("/api/auth/refresh", post(handlers::refresh))
+        .route("/api/auth/jwks", get(keys::jwks))
+        .route("/metrics", get(resilience::metrics))
         .with_state(Arc::new(state));
 
     tracing::info!("routs {:?}", router);
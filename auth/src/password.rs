@@ -0,0 +1,83 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+
+pub struct HashedPassword {
+    pub hash: String,
+    pub salt: String,
+}
+
+/// Hashes `password` with Argon2id and a fresh random salt off the async runtime.
+pub async fn hash_password(password: String) -> anyhow::Result<HashedPassword> {
+    tokio::task::spawn_blocking(move || hash_password_blocking(&password)).await?
+}
+
+/// Checks `password` against a PHC hash produced by [`hash_password`] off the async runtime.
+///
+/// Without a hash, e.g. for an unknown login, a dummy one is checked and `false` returned, so
+/// the answer takes as long as for a wrong password.
+pub async fn verify_password(password: String, hash: Option<String>) -> anyhow::Result<bool> {
+    tokio::task::spawn_blocking(move || match hash {
+        Some(hash) => verify_password_blocking(&password, &hash),
+        None => {
+            verify_password_blocking(&password, dummy_hash()?)?;
+            Ok(false)
+        }
+    })
+    .await?
+}
+
+fn dummy_hash() -> anyhow::Result<&'static str> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash);
+    }
+    let hashed = hash_password_blocking(SaltString::generate(&mut OsRng).as_str())?;
+    Ok(DUMMY_HASH.get_or_init(|| hashed.hash))
+}
+
+fn hash_password_blocking(password: &str) -> anyhow::Result<HashedPassword> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow::anyhow!("Failed to hash password: {}", err))?;
+
+    Ok(HashedPassword {
+        hash: hash.to_string(),
+        salt: salt.as_str().to_string(),
+    })
+}
+
+fn verify_password_blocking(password: &str, hash: &str) -> anyhow::Result<bool> {
+    let hash =
+        PasswordHash::new(hash).map_err(|err| anyhow::anyhow!("Invalid password hash: {}", err))?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hashes_and_verifies_passwords() {
+        let hashed = hash_password("correct horse".to_string()).await.unwrap();
+        assert!(hashed.hash.contains(&hashed.salt));
+
+        let hash = Some(hashed.hash);
+        assert!(verify_password("correct horse".to_string(), hash.clone())
+            .await
+            .unwrap());
+        assert!(!verify_password("battery staple".to_string(), hash)
+            .await
+            .unwrap());
+        assert!(!verify_password("correct horse".to_string(), None)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::Json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey,
+};
+use mongodb::bson::oid::ObjectId;
+use once_cell::sync::{Lazy, OnceCell};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use crate::services::{AUTH, REGISTRY};
+
+/// Issuer trusted with every role. Other issuers may only sign `Auth::Service` tokens
+/// carrying their own name.
+pub const AUTHORITY: &str = "auth";
+
+/// Remote key sets are refetched at least this often, so retired keys stop being accepted.
+const JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Unknown `kid`s trigger a refetch, but not more often than this.
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keys used by this service, configured from the environment:
+///
+/// * `SERVICE_NAME` – issuer name of this service, only read when `KEYS` is used before
+///   `ServiceState::new` names the service;
+/// * `JWT_SIGNING_KEY` – path to an Ed25519 PKCS#8 PEM private key. Services other than
+///   the authority generate a key on start without it; the authority only verifies tokens;
+/// * `JWT_SIGNING_KID` – key id published for the signing key;
+/// * `JWT_RETIRED_KEYS` – path to a JWKS file with previous public keys, kept published until
+///   every token they signed has expired;
+/// * `JWKS_URL` – where the keys of the authority are fetched from.
+///
+/// Keys of other issuers are fetched from `/api/<issuer>/jwks` on the service the
+/// `ServiceRegistry` resolves for them.
+pub static KEYS: Lazy<KeyStore> = Lazy::new(|| KeyStore::from_env().expect("Invalid JWT keys"));
+
+/// Name of this service, set by `ServiceState::new`.
+static SERVICE_NAME: OnceCell<String> = OnceCell::new();
+
+/// Names the issuer of `KEYS`, unless it is already named.
+pub fn set_service_name(name: &str) {
+    let _ = SERVICE_NAME.set(name.to_string());
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    key: EncodingKey,
+    jwk: Jwk,
+}
+
+impl SigningKey {
+    pub fn from_pkcs8(kid: String, der: &[u8]) -> anyhow::Result<Self> {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+            .map_err(|err| anyhow::anyhow!("Invalid Ed25519 key: {}", err))?;
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(Algorithm::EdDSA),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            }),
+        };
+
+        Ok(Self {
+            kid,
+            key: EncodingKey::from_ed_der(der),
+            jwk,
+        })
+    }
+
+    pub fn from_pem(kid: String, pem: &[u8]) -> anyhow::Result<Self> {
+        let pem = pem::parse(pem)?;
+        Self::from_pkcs8(kid, &pem.contents)
+    }
+
+    /// Generates a throwaway key, for local development and tests.
+    pub fn generate(kid: String) -> anyhow::Result<Self> {
+        let der = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("Failed to generate Ed25519 key"))?;
+        Self::from_pkcs8(kid, der.as_ref())
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.key
+    }
+}
+
+struct IssuerKeys {
+    decoding: HashMap<String, DecodingKey>,
+    fetched_at: Option<Instant>,
+}
+
+impl IssuerKeys {
+    fn new(jwks: &JwkSet) -> anyhow::Result<Self> {
+        let mut decoding = HashMap::new();
+        for jwk in &jwks.keys {
+            let Some(kid) = &jwk.common.key_id else {
+                continue;
+            };
+            decoding.insert(kid.clone(), DecodingKey::from_jwk(jwk)?);
+        }
+        Ok(Self {
+            decoding,
+            fetched_at: None,
+        })
+    }
+}
+
+pub struct KeyStore {
+    name: String,
+    signing: Option<SigningKey>,
+    published: JwkSet,
+    issuers: RwLock<HashMap<String, IssuerKeys>>,
+}
+
+impl KeyStore {
+    /// Key store of the issuer `name`, signing with `signing` and publishing it next to
+    /// `retired`. Tokens of other issuers are verified with keys fetched on demand.
+    pub fn new(name: String, signing: Option<SigningKey>, retired: JwkSet) -> anyhow::Result<Self> {
+        let mut published = retired;
+        if let Some(signing) = &signing {
+            published
+                .keys
+                .retain(|jwk| jwk.common.key_id.as_ref() != Some(&signing.kid));
+            published.keys.insert(0, signing.jwk.clone());
+        }
+
+        let own = IssuerKeys::new(&published)?;
+        Ok(Self {
+            issuers: RwLock::new(HashMap::from([(name.clone(), own)])),
+            name,
+            signing,
+            published,
+        })
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let name = match SERVICE_NAME.get() {
+            Some(name) => name.clone(),
+            None => std::env::var("SERVICE_NAME").context("SERVICE_NAME must be set")?,
+        };
+
+        let signing = match std::env::var("JWT_SIGNING_KEY") {
+            Ok(path) => {
+                let kid =
+                    std::env::var("JWT_SIGNING_KID").context("JWT_SIGNING_KID must be set")?;
+                let pem =
+                    std::fs::read(&path).with_context(|| format!("Failed to read {}", path))?;
+                Some(SigningKey::from_pem(kid, &pem)?)
+            }
+            // Peers fetch the key from our JWKS route, so any key works for service tokens.
+            Err(_) if name != AUTHORITY => {
+                tracing::warn!(
+                    "JWT_SIGNING_KEY is not set, {} signs with a generated key",
+                    name
+                );
+                let kid = format!("{}-{}", name, ObjectId::new().to_hex());
+                Some(SigningKey::generate(kid)?)
+            }
+            Err(_) => None,
+        };
+
+        let retired = match std::env::var("JWT_RETIRED_KEYS") {
+            Ok(path) => {
+                let file =
+                    std::fs::read(&path).with_context(|| format!("Failed to read {}", path))?;
+                serde_json::from_slice(&file)?
+            }
+            Err(_) => JwkSet { keys: Vec::new() },
+        };
+
+        Self::new(name, signing, retired)
+    }
+
+    /// Issuer name put into the tokens this store signs.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.signing.as_ref()
+    }
+
+    /// Public keys of this service, as served from its JWKS endpoint.
+    pub fn jwks(&self) -> JwkSet {
+        self.published.clone()
+    }
+
+    /// Key `kid` together with the issuer it belongs to. Key ids must be unique across issuers.
+    pub fn decoding_key(&self, kid: &str) -> Option<(String, DecodingKey)> {
+        let issuers = self.issuers.read().unwrap();
+        issuers.iter().find_map(|(issuer, keys)| {
+            keys.decoding
+                .get(kid)
+                .map(|key| (issuer.clone(), key.clone()))
+        })
+    }
+
+    /// Replaces the trusted keys of `issuer`.
+    pub fn trust(&self, issuer: &str, jwks: &JwkSet) -> anyhow::Result<()> {
+        let mut keys = IssuerKeys::new(jwks)?;
+        keys.fetched_at = Some(Instant::now());
+        self.issuers
+            .write()
+            .unwrap()
+            .insert(issuer.to_string(), keys);
+        Ok(())
+    }
+
+    fn jwks_url(issuer: &str) -> anyhow::Result<String> {
+        if issuer == AUTHORITY {
+            return Ok(std::env::var("JWKS_URL")
+                .unwrap_or_else(|_| format!("{}/api/{}/jwks", *AUTH, AUTHORITY)));
+        }
+        Ok(format!("{}/api/{}/jwks", REGISTRY.resolve(issuer)?, issuer))
+    }
+
+    pub(super) fn needs_refresh(&self, issuer: &str, kid: Option<&str>) -> bool {
+        // Own keys are only complete when this store signs. An authority without a signing
+        // key verifies with the keys fetched from `JWKS_URL` instead.
+        if issuer == self.name && self.signing.is_some() {
+            return false;
+        }
+        let issuers = self.issuers.read().unwrap();
+        let Some(fetched_at) = issuers.get(issuer).and_then(|keys| keys.fetched_at) else {
+            return true;
+        };
+        let elapsed = fetched_at.elapsed();
+        let unknown =
+            kid.is_some_and(|kid| !issuers.values().any(|keys| keys.decoding.contains_key(kid)));
+
+        elapsed > JWKS_TTL || (unknown && elapsed > JWKS_MIN_REFRESH_INTERVAL)
+    }
+
+    pub async fn refresh(&self, client: &reqwest::Client, issuer: &str) -> anyhow::Result<()> {
+        let jwks: JwkSet = client
+            .get(Self::jwks_url(issuer)?)
+            .send()
+            .await?
+            .json()
+            .await?;
+        self.trust(issuer, &jwks)
+    }
+
+    /// Makes sure the key that signed `token` is cached, refetching the key set of its issuer
+    /// when the `kid` is unknown or the cache is stale.
+    ///
+    /// The unverified `iss` claim only selects where keys are fetched from; which issuer a
+    /// token belongs to is decided by the key that verifies it.
+    pub async fn prepare(&self, client: &reqwest::Client, token: &str) -> anyhow::Result<()> {
+        let kid = jsonwebtoken::decode_header(token)
+            .ok()
+            .and_then(|header| header.kid);
+        let issuer = unverified_issuer(token).unwrap_or_else(|| AUTHORITY.to_string());
+        if issuer != AUTHORITY && REGISTRY.get(&issuer).is_none() {
+            // Only services configured in the registry are trusted as issuers.
+            return Ok(());
+        }
+
+        if self.needs_refresh(&issuer, kid.as_deref()) {
+            self.refresh(client, &issuer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves the public keys of this service, mounted at `/api/<service>/jwks`.
+pub async fn jwks() -> Json<JwkSet> {
+    Json(KEYS.jwks())
+}
+
+fn unverified_issuer(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("iss")?.as_str().map(str::to_string)
+}
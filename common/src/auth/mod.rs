@@ -1,100 +1,525 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use anyhow::bail;
-use jsonwebtoken::{decode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, errors::ErrorKind, Algorithm, Header, Validation};
 use mongodb::bson::oid::ObjectId;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-pub static ENCODING_KEY: Lazy<EncodingKey> = Lazy::new(|| {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    EncodingKey::from_secret(secret.as_bytes())
-});
+use crate::error::ServiceError;
 
-pub static DECODING_KEY: Lazy<DecodingKey> = Lazy::new(|| {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    DecodingKey::from_secret(secret.as_bytes())
-});
+use self::keys::{KeyStore, AUTHORITY, KEYS};
 
-#[derive(Debug, Clone)]
+pub mod keys;
+pub mod policy;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Auth {
     Service(String),
     Admin(ObjectId),
     User(ObjectId),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MalformedHeader,
+    MalformedToken(String),
+    UnknownKey(String),
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    WrongTokenType,
+    MissingClaim(String),
+    UnknownRole,
+    InvalidUserId,
+    UntrustedIssuer(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MalformedHeader => write!(f, "Authorization header must be a bearer token"),
+            AuthError::MalformedToken(err) => write!(f, "Malformed token: {}", err),
+            AuthError::UnknownKey(kid) => write!(f, "Token signed with unknown key `{}`", kid),
+            AuthError::InvalidSignature => write!(f, "Invalid token signature"),
+            AuthError::Expired => write!(f, "Token expired"),
+            AuthError::NotYetValid => write!(f, "Token is not valid yet"),
+            AuthError::WrongTokenType => write!(f, "Wrong token type"),
+            AuthError::MissingClaim(claim) => write!(f, "Token is missing claim `{}`", claim),
+            AuthError::UnknownRole => write!(f, "Token has unknown role"),
+            AuthError::InvalidUserId => write!(f, "Token has invalid user id"),
+            AuthError::UntrustedIssuer(issuer) => {
+                write!(f, "Issuer `{}` can't sign this token", issuer)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<AuthError> for ServiceError {
+    fn from(err: AuthError) -> Self {
+        ServiceError::Unauthorized(err.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+            ErrorKind::ExpiredSignature => AuthError::Expired,
+            ErrorKind::ImmatureSignature => AuthError::NotYetValid,
+            ErrorKind::MissingRequiredClaim(claim) => AuthError::MissingClaim(claim.clone()),
+            _ => AuthError::MalformedToken(err.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum Role {
     Admin,
     User,
     Service,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TokenType {
+    #[default]
+    Access,
+    Refresh,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Claims {
-    role: Role,
+    role: Option<Role>,
     user_id: Option<String>,
     service_name: Option<String>,
-    exp: i64,
+    #[serde(default)]
+    token_type: TokenType,
+    iss: Option<String>,
+    exp: Option<i64>,
+    iat: Option<i64>,
+    nbf: Option<i64>,
+    jti: Option<String>,
+}
+
+/// Token lifetimes in seconds, read from `ACCESS_TOKEN_TTL_ADMIN`,
+/// `ACCESS_TOKEN_TTL_USER`, `ACCESS_TOKEN_TTL_SERVICE` and `REFRESH_TOKEN_TTL`.
+#[derive(Debug, Clone)]
+pub struct TokenLifetimes {
+    pub admin: Duration,
+    pub user: Duration,
+    pub service: Duration,
+    pub refresh: Duration,
+}
+
+fn lifetime_from_env(name: &str, default: i64) -> Duration {
+    let seconds = std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+    Duration::seconds(seconds)
+}
+
+impl TokenLifetimes {
+    pub fn from_env() -> Self {
+        Self {
+            admin: lifetime_from_env("ACCESS_TOKEN_TTL_ADMIN", 15 * 60),
+            user: lifetime_from_env("ACCESS_TOKEN_TTL_USER", 60 * 60),
+            service: lifetime_from_env("ACCESS_TOKEN_TTL_SERVICE", 5 * 60),
+            refresh: lifetime_from_env("REFRESH_TOKEN_TTL", 30 * 24 * 60 * 60),
+        }
+    }
+}
+
+pub static TOKEN_LIFETIMES: Lazy<TokenLifetimes> = Lazy::new(TokenLifetimes::from_env);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+fn user_id(claims: &Claims) -> Result<ObjectId, AuthError> {
+    let Some(user_id) = &claims.user_id else {
+        return Err(AuthError::MissingClaim("user_id".to_string()));
+    };
+    ObjectId::from_str(user_id).map_err(|_| AuthError::InvalidUserId)
 }
 
 impl Auth {
-    pub fn from_token(token: &str) -> anyhow::Result<Self> {
-        match decode::<Claims>(&token, &DECODING_KEY, &Validation::new(Algorithm::HS512)) {
-            Ok(c) => {
-                let claims = c.claims;
-                match claims.role {
-                    Role::Admin => {
-                        let id = ObjectId::from_str(&claims.user_id.unwrap())?;
-                        Ok(Auth::Admin(id))
-                    }
-                    Role::User => {
-                        let id = ObjectId::from_str(&claims.user_id.unwrap())?;
-                        Ok(Auth::User(id))
-                    }
-                    Role::Service => {
-                        let name = claims.service_name.unwrap();
-                        Ok(Auth::Service(name))
-                    }
-                }
-            }
-            Err(err) => {
-                panic!("Error: {:?}", err);
+    /// Validates an access token. Refresh tokens are rejected.
+    pub fn from_token(token: &str) -> Result<Self, AuthError> {
+        Self::decode(token, TokenType::Access, &KEYS)
+    }
+
+    /// Validates a refresh token. Access tokens are rejected.
+    pub fn from_refresh_token(token: &str) -> Result<Self, AuthError> {
+        Self::decode(token, TokenType::Refresh, &KEYS)
+    }
+
+    fn decode(token: &str, token_type: TokenType, keys: &KeyStore) -> Result<Self, AuthError> {
+        let Some(kid) = jsonwebtoken::decode_header(token)?.kid else {
+            return Err(AuthError::MissingClaim("kid".to_string()));
+        };
+        let Some((issuer, key)) = keys.decoding_key(&kid) else {
+            return Err(AuthError::UnknownKey(kid));
+        };
+
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.validate_nbf = true;
+
+        let claims = decode::<Claims>(token, &key, &validation)?.claims;
+
+        if claims.token_type != token_type {
+            return Err(AuthError::WrongTokenType);
+        }
+        if claims.iss.as_ref().is_some_and(|iss| *iss != issuer) {
+            return Err(AuthError::UntrustedIssuer(issuer));
+        }
+
+        let auth = match claims.role {
+            Some(Role::Admin) => Auth::Admin(user_id(&claims)?),
+            Some(Role::User) => Auth::User(user_id(&claims)?),
+            Some(Role::Service) => {
+                let Some(name) = claims.service_name else {
+                    return Err(AuthError::MissingClaim("service_name".to_string()));
+                };
+                Auth::Service(name)
             }
+            Some(Role::Unknown) => return Err(AuthError::UnknownRole),
+            None => return Err(AuthError::MissingClaim("role".to_string())),
+        };
+
+        // Services sign their own tokens, but can only speak for themselves.
+        match &auth {
+            _ if issuer == AUTHORITY => Ok(auth),
+            Auth::Service(name) if *name == issuer => Ok(auth),
+            _ => Err(AuthError::UntrustedIssuer(issuer)),
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        match self {
+            Auth::Service(_) => TOKEN_LIFETIMES.service,
+            Auth::Admin(_) => TOKEN_LIFETIMES.admin,
+            Auth::User(_) => TOKEN_LIFETIMES.user,
         }
     }
 
     pub fn to_token(&self) -> anyhow::Result<String> {
+        self.encode(TokenType::Access, self.lifetime(), &KEYS)
+    }
+
+    pub fn to_refresh_token(&self) -> anyhow::Result<String> {
+        self.encode(TokenType::Refresh, TOKEN_LIFETIMES.refresh, &KEYS)
+    }
+
+    pub fn to_token_pair(&self) -> anyhow::Result<TokenPair> {
+        Ok(TokenPair {
+            access_token: self.to_token()?,
+            refresh_token: self.to_refresh_token()?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.lifetime().num_seconds(),
+        })
+    }
+
+    fn encode(
+        &self,
+        token_type: TokenType,
+        lifetime: Duration,
+        keys: &KeyStore,
+    ) -> anyhow::Result<String> {
+        let Some(signing_key) = keys.signing_key() else {
+            bail!("No signing key configured");
+        };
+
         let header = Header {
-            alg: Algorithm::HS512,
+            alg: Algorithm::EdDSA,
+            kid: Some(signing_key.kid.clone()),
             ..Default::default()
         };
 
-        let claims = match self {
-            Auth::Service(name) => Claims {
-                role: Role::Service,
-                user_id: None,
-                service_name: Some(name.clone()),
-                exp: 0,
-            },
-            Auth::Admin(id) => Claims {
-                role: Role::Admin,
-                user_id: Some(id.to_hex()),
-                service_name: None,
-                exp: 0,
-            },
-            Auth::User(id) => Claims {
-                role: Role::User,
-                user_id: Some(id.to_hex()),
-                service_name: None,
-                exp: 0,
-            },
+        let (role, user_id, service_name) = match self {
+            Auth::Service(name) => (Role::Service, None, Some(name.clone())),
+            Auth::Admin(id) => (Role::Admin, Some(id.to_hex()), None),
+            Auth::User(id) => (Role::User, Some(id.to_hex()), None),
+        };
+
+        let now = Utc::now();
+        let claims = Claims {
+            role: Some(role),
+            user_id,
+            service_name,
+            token_type,
+            iss: Some(keys.name().to_string()),
+            exp: Some((now + lifetime).timestamp()),
+            iat: Some(now.timestamp()),
+            nbf: Some(now.timestamp()),
+            jti: Some(ObjectId::new().to_hex()),
         };
 
-        let token = match jsonwebtoken::encode(&header, &claims, &ENCODING_KEY) {
+        let token = match jsonwebtoken::encode(&header, &claims, signing_key.encoding_key()) {
             Ok(t) => t,
             Err(_) => bail!("Failed to encode token"),
         };
         Ok(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, jwk::JwkSet, Header};
+    use serde_json::{json, Value};
+
+    use super::{keys::SigningKey, *};
+
+    fn store(kid: &str) -> KeyStore {
+        issuer_store(AUTHORITY, kid)
+    }
+
+    fn issuer_store(name: &str, kid: &str) -> KeyStore {
+        let key = SigningKey::generate(kid.to_string()).unwrap();
+        KeyStore::new(name.to_string(), Some(key), JwkSet { keys: Vec::new() }).unwrap()
+    }
+
+    fn token(claims: Value, keys: &KeyStore) -> String {
+        let key = keys.signing_key().unwrap();
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(key.kid.clone());
+        encode(&header, &claims, key.encoding_key()).unwrap()
+    }
+
+    fn access(token: &str, keys: &KeyStore) -> Result<Auth, AuthError> {
+        Auth::decode(token, TokenType::Access, keys)
+    }
+
+    fn future() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let keys = store("current");
+        let id = ObjectId::new();
+        let token = token(
+            json!({ "role": "User", "user_id": id.to_hex(), "exp": future() }),
+            &keys,
+        );
+        assert_eq!(access(&token, &keys), Ok(Auth::User(id)));
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let keys = store("current");
+        let forged = store("current");
+        let token = token(
+            json!({ "role": "Service", "service_name": "user", "exp": future() }),
+            &forged,
+        );
+        assert_eq!(access(&token, &keys), Err(AuthError::InvalidSignature));
+    }
+
+    #[test]
+    fn rejects_unknown_key() {
+        let keys = store("current");
+        let token = token(
+            json!({ "role": "Service", "service_name": "user", "exp": future() }),
+            &store("other"),
+        );
+        assert_eq!(
+            access(&token, &keys),
+            Err(AuthError::UnknownKey("other".to_string()))
+        );
+    }
+
+    #[test]
+    fn accepts_retired_keys() {
+        let old = store("old");
+        let current = SigningKey::generate("current".to_string()).unwrap();
+        let keys = KeyStore::new(AUTHORITY.to_string(), Some(current), old.jwks()).unwrap();
+
+        let id = ObjectId::new();
+        let token = token(
+            json!({ "role": "Admin", "user_id": id.to_hex(), "exp": future() }),
+            &old,
+        );
+        assert_eq!(access(&token, &keys), Ok(Auth::Admin(id)));
+        assert_eq!(keys.jwks().keys.len(), 2);
+    }
+
+    #[test]
+    fn restricts_service_issuers() {
+        let keys = store("auth-key");
+        let project = issuer_store("project", "project-key");
+        keys.trust("project", &project.jwks()).unwrap();
+
+        let own = Auth::Service("project".to_string());
+        let token = own
+            .encode(TokenType::Access, own.lifetime(), &project)
+            .unwrap();
+        assert_eq!(access(&token, &keys), Ok(own));
+
+        let other = Auth::Service("auth".to_string());
+        let token = other
+            .encode(TokenType::Access, other.lifetime(), &project)
+            .unwrap();
+        assert_eq!(
+            access(&token, &keys),
+            Err(AuthError::UntrustedIssuer("project".to_string()))
+        );
+
+        let admin = Auth::Admin(ObjectId::new());
+        let token = admin
+            .encode(TokenType::Access, admin.lifetime(), &project)
+            .unwrap();
+        assert_eq!(
+            access(&token, &keys),
+            Err(AuthError::UntrustedIssuer("project".to_string()))
+        );
+    }
+
+    #[test]
+    fn verifies_authority_tokens_in_other_services() {
+        let auth = store("auth-key");
+        let user = issuer_store("user", "user-key");
+        // Named like the authority, but without its signing key.
+        let verifier =
+            KeyStore::new(AUTHORITY.to_string(), None, JwkSet { keys: Vec::new() }).unwrap();
+
+        let id = ObjectId::new();
+        let token = Auth::User(id).encode(TokenType::Access, Auth::User(id).lifetime(), &auth);
+        let token = token.unwrap();
+        for keys in [&user, &verifier] {
+            assert!(keys.needs_refresh(AUTHORITY, Some("auth-key")));
+            assert_eq!(
+                access(&token, keys),
+                Err(AuthError::UnknownKey("auth-key".to_string()))
+            );
+
+            keys.trust(AUTHORITY, &auth.jwks()).unwrap();
+            assert!(!keys.needs_refresh(AUTHORITY, Some("auth-key")));
+            assert_eq!(access(&token, keys), Ok(Auth::User(id)));
+        }
+        assert!(!auth.needs_refresh(AUTHORITY, Some("auth-key")));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let keys = store("current");
+        let token = token(
+            json!({ "role": "Service", "service_name": "user", "exp": 1 }),
+            &keys,
+        );
+        assert_eq!(access(&token, &keys), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn rejects_missing_claims() {
+        let keys = store("current");
+
+        let no_exp = token(json!({ "role": "Service", "service_name": "user" }), &keys);
+        assert_eq!(
+            access(&no_exp, &keys),
+            Err(AuthError::MissingClaim("exp".to_string()))
+        );
+
+        let no_role = token(json!({ "service_name": "user", "exp": future() }), &keys);
+        assert_eq!(
+            access(&no_role, &keys),
+            Err(AuthError::MissingClaim("role".to_string()))
+        );
+
+        let no_user_id = token(json!({ "role": "Admin", "exp": future() }), &keys);
+        assert_eq!(
+            access(&no_user_id, &keys),
+            Err(AuthError::MissingClaim("user_id".to_string()))
+        );
+
+        let no_service_name = token(json!({ "role": "Service", "exp": future() }), &keys);
+        assert_eq!(
+            access(&no_service_name, &keys),
+            Err(AuthError::MissingClaim("service_name".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_role() {
+        let keys = store("current");
+        let token = token(
+            json!({ "role": "Root", "user_id": ObjectId::new().to_hex(), "exp": future() }),
+            &keys,
+        );
+        assert_eq!(access(&token, &keys), Err(AuthError::UnknownRole));
+    }
+
+    #[test]
+    fn rejects_invalid_user_id() {
+        let keys = store("current");
+        let token = token(
+            json!({ "role": "User", "user_id": "not-an-id", "exp": future() }),
+            &keys,
+        );
+        assert_eq!(access(&token, &keys), Err(AuthError::InvalidUserId));
+    }
+
+    #[test]
+    fn round_trips_tokens() {
+        let keys = store("current");
+        let auth = Auth::Admin(ObjectId::new());
+        let lifetime = auth.lifetime();
+
+        let access_token = auth.encode(TokenType::Access, lifetime, &keys).unwrap();
+        let refresh_token = auth.encode(TokenType::Refresh, lifetime, &keys).unwrap();
+
+        assert_eq!(access(&access_token, &keys), Ok(auth.clone()));
+        assert_eq!(
+            Auth::decode(&refresh_token, TokenType::Refresh, &keys),
+            Ok(auth)
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_token_type() {
+        let keys = store("current");
+        let auth = Auth::Service("user".to_string());
+        let lifetime = auth.lifetime();
+
+        let access_token = auth.encode(TokenType::Access, lifetime, &keys).unwrap();
+        let refresh_token = auth.encode(TokenType::Refresh, lifetime, &keys).unwrap();
+
+        assert_eq!(
+            access(&refresh_token, &keys),
+            Err(AuthError::WrongTokenType)
+        );
+        assert_eq!(
+            Auth::decode(&access_token, TokenType::Refresh, &keys),
+            Err(AuthError::WrongTokenType)
+        );
+    }
+
+    #[test]
+    fn rejects_token_before_nbf() {
+        let keys = store("current");
+        let token = token(
+            json!({ "role": "Service", "service_name": "user", "exp": future(), "nbf": future() }),
+            &keys,
+        );
+        assert_eq!(access(&token, &keys), Err(AuthError::NotYetValid));
+    }
+
+    #[test]
+    fn rejects_malformed_token() {
+        let keys = store("current");
+        assert!(matches!(
+            access("not-a-token", &keys),
+            Err(AuthError::MalformedToken(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use mongodb::bson::{doc, oid::ObjectId, to_document, Document};
+use serde::Serialize;
+
+use crate::{
+    context::{Context, Viewer},
+    entity::Entity,
+    error::ServiceError,
+    repository::Method,
+};
+
+use super::Auth;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    /// Allowed only for documents whose owner fields contain the caller's id.
+    Owner,
+    Deny,
+}
+
+/// Access rules for the routes generated by `Registrable::register`.
+///
+/// `Auth::Service` callers bypass the policy unless they act on behalf of an end user.
+/// `Access::Owner` is checked against `Entity::OWNER_FIELDS`.
+pub trait Policy {
+    fn access(auth: Option<&Auth>, method: Method) -> Access;
+}
+
+pub fn caller_id(auth: Option<&Auth>) -> Option<ObjectId> {
+    match auth {
+        Some(Auth::Admin(id)) | Some(Auth::User(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+/// Checks that the caller may run `method` on `T`, returning the granted access.
+pub fn check_access<T: Policy>(
+    auth: Option<&Auth>,
+    method: Method,
+) -> Result<Access, ServiceError> {
+    if let Some(Auth::Service(_)) = auth {
+        return Ok(Access::Allow);
+    }
+
+    match (T::access(auth, method), auth) {
+        (Access::Allow, _) => Ok(Access::Allow),
+        (_, None) => Err(ServiceError::Unauthorized(
+            "Authentication required".to_string(),
+        )),
+        (Access::Owner, Some(_)) => Ok(Access::Owner),
+        (Access::Deny, Some(_)) => Err(ServiceError::Forbidden(format!(
+            "{:?} is not allowed",
+            method
+        ))),
+    }
+}
+
+pub fn is_owner<T: Entity<T> + Serialize>(entity: &T, auth: Option<&Auth>) -> anyhow::Result<bool> {
+    let Some(id) = caller_id(auth) else {
+        return Ok(false);
+    };
+    let document = to_document(entity)?;
+
+    Ok(T::OWNER_FIELDS
+        .iter()
+        .any(|field| document.get_object_id(field) == Ok(id)))
+}
+
+/// Relation of the end user of `context` to `entity`.
+pub async fn viewer<T>(entity: &T, context: &Context) -> anyhow::Result<Viewer>
+where
+    T: Entity<T> + Serialize + Sync,
+{
+    let auth = context.1.end_user();
+    let id = match auth {
+        None => return Ok(Viewer::Anonymous),
+        Some(Auth::Admin(_)) | Some(Auth::Service(_)) => return Ok(Viewer::Admin),
+        Some(Auth::User(id)) => *id,
+    };
+
+    if is_owner(entity, auth)? {
+        Ok(Viewer::Owner)
+    } else if entity.is_counterpart(id, context).await? {
+        Ok(Viewer::Counterpart)
+    } else {
+        Ok(Viewer::User)
+    }
+}
+
+/// Fails with `Forbidden` when `access` is `Access::Owner` and the caller doesn't own `entity`.
+pub fn check_owner<T: Entity<T> + Serialize>(
+    entity: &T,
+    auth: Option<&Auth>,
+    access: Access,
+) -> Result<(), ServiceError> {
+    if access == Access::Owner && !is_owner(entity, auth)? {
+        return Err(ServiceError::Forbidden(
+            "Document belongs to another user".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Row level security: `Auth::User` callers only see and mutate documents they own.
+///
+/// Admins, services and internal calls without a user are not scoped; anonymous requests
+/// are rejected by the route policies before reaching a repository.
+pub fn scoped_user<T: Entity<T>>(auth: Option<&Auth>) -> Option<ObjectId> {
+    match auth {
+        Some(Auth::User(id)) if !T::OWNER_FIELDS.is_empty() => Some(*id),
+        _ => None,
+    }
+}
+
+/// Restricts `filter` to the documents owned by the caller, see [`scoped_user`].
+pub fn scope_filter<T: Entity<T>>(filter: Document, auth: Option<&Auth>) -> Document {
+    let Some(id) = scoped_user::<T>(auth) else {
+        return filter;
+    };
+    let owners: Vec<Document> = T::OWNER_FIELDS
+        .iter()
+        .map(|field| doc! { *field: id })
+        .collect();
+
+    doc! { "$and": [filter, { "$or": owners }] }
+}
+
+/// Fails with `Forbidden` when a scoped caller writes a document it doesn't own.
+pub fn check_scope<T: Entity<T> + Serialize>(
+    entity: &T,
+    auth: Option<&Auth>,
+) -> Result<(), ServiceError> {
+    if scoped_user::<T>(auth).is_some() && !is_owner(entity, auth)? {
+        return Err(ServiceError::Forbidden(
+            "Document belongs to another user".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    struct Note;
+
+    impl Policy for Note {
+        fn access(_: Option<&Auth>, method: Method) -> Access {
+            match method {
+                Method::Find => Access::Allow,
+                Method::Update => Access::Owner,
+                _ => Access::Deny,
+            }
+        }
+    }
+
+    #[test]
+    fn checks_access() {
+        let service = Auth::Service("project".to_string());
+        let user = Auth::User(ObjectId::new());
+
+        for (auth, method, expected) in [
+            (Some(&service), Method::Delete, Ok(Access::Allow)),
+            (None, Method::Find, Ok(Access::Allow)),
+            (None, Method::Update, Err(ErrorCode::Unauthorized)),
+            (None, Method::Delete, Err(ErrorCode::Unauthorized)),
+            (Some(&user), Method::Update, Ok(Access::Owner)),
+            (Some(&user), Method::Delete, Err(ErrorCode::Forbidden)),
+        ] {
+            let result = check_access::<Note>(auth, method).map_err(|err| err.code());
+            assert_eq!(result, expected, "{:?} {:?}", auth, method);
+        }
+    }
+}
@@ -1,14 +1,24 @@
 use std::sync::Arc;
 
-use anyhow::bail;
+use anyhow::Context as _;
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use mongodb::bson::oid::ObjectId;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
 use serde::Serialize;
 use type_map::concurrent::TypeMap;
 
 use crate::{
-    auth::Auth,
+    auth::{
+        keys::{self, KEYS},
+        policy::caller_id,
+        Auth, AuthError,
+    },
     error::ServiceError,
     repository::{Repository, RepositoryTrait},
+    resilience::{peer_name, CallPolicy, PEERS},
 };
 
 pub struct ServiceState {
@@ -19,6 +29,7 @@ pub struct ServiceState {
 
 impl ServiceState {
     pub fn new(service_name: String) -> Self {
+        keys::set_service_name(&service_name);
         Self {
             repositories: TypeMap::new(),
             client: reqwest::Client::new(),
@@ -31,21 +42,44 @@ impl ServiceState {
     }
 }
 
+/// Header carrying the token of the end user a service call is made for.
+pub const ON_BEHALF_OF: &str = "X-On-Behalf-Of";
+
 pub struct HandlerContext {
     pub user_auth: Option<Auth>,
+    /// End user a calling service acts for, taken from the `X-On-Behalf-Of` header.
+    pub on_behalf_of: Option<Auth>,
+    /// Token of the end user, forwarded on requests made while handling this one.
+    pub forwarded_token: Option<String>,
+}
+
+impl HandlerContext {
+    /// Caller the request is ultimately made for, which policies and row level security apply to.
+    pub fn end_user(&self) -> Option<&Auth> {
+        self.on_behalf_of.as_ref().or(self.user_auth.as_ref())
+    }
+
+    /// Whether the request comes directly from another service, regardless of whom it acts for.
+    pub fn is_service(&self) -> bool {
+        matches!(self.user_auth, Some(Auth::Service(_)))
+    }
 }
 
 pub struct Context(pub Arc<ServiceState>, pub HandlerContext);
 
 pub struct ContextExtractor(pub Context);
 
-pub fn extract_token(parts: &Parts) -> anyhow::Result<Option<&str>> {
+pub fn extract_token(parts: &Parts) -> Result<Option<&str>, AuthError> {
     let Some(header) = parts.headers.get("Authorization") else {
-        return Ok(None)
+        return Ok(None);
     };
 
-    let Some(token) = header.to_str()?.strip_prefix("Bearer ") else {
-        bail!("wrong token format")
+    let Some(token) = header
+        .to_str()
+        .ok()
+        .and_then(|header| header.strip_prefix("Bearer "))
+    else {
+        return Err(AuthError::MalformedHeader);
     };
 
     Ok(Some(token))
@@ -60,79 +94,267 @@ impl FromRequestParts<Arc<ServiceState>> for ContextExtractor {
         state: &Arc<ServiceState>,
     ) -> Result<Self, Self::Rejection> {
         let mut user_auth = None;
+        let mut on_behalf_of = None;
+        let mut forwarded_token = None;
 
         if let Some(token) = extract_token(parts)? {
-            user_auth = Some(Auth::from_token(token)?);
+            let auth = verify(state, token).await?;
+            if !matches!(auth, Auth::Service(_)) {
+                forwarded_token = Some(token.to_string());
+            }
+            user_auth = Some(auth);
+        }
+
+        // Only services may act for someone else.
+        if let Some(Auth::Service(_)) = user_auth {
+            if let Some(header) = parts.headers.get(ON_BEHALF_OF) {
+                let token = header.to_str().map_err(|_| AuthError::MalformedHeader)?;
+                on_behalf_of = Some(verify(state, token).await?);
+                forwarded_token = Some(token.to_string());
+            }
         }
 
         Ok(ContextExtractor(Context(
             Arc::clone(state),
-            HandlerContext { user_auth },
+            HandlerContext {
+                user_auth,
+                on_behalf_of,
+                forwarded_token,
+            },
         )))
     }
 }
 
-pub struct ServiceRequest<'a, 'b, T = ()> {
-    // TODO: return error, if we try to send body into GET request
+async fn verify(state: &ServiceState, token: &str) -> Result<Auth, AuthError> {
+    if let Err(err) = KEYS.prepare(&state.client, token).await {
+        tracing::warn!("failed to refresh JWT keys: {:?}", err);
+    }
+    Auth::from_token(token)
+}
+
+/// Request state before a method and URL are chosen.
+pub struct Unaddressed;
+
+/// `GET` and `DELETE` requests, which can't carry a body.
+pub struct Bodiless {
+    url: String,
+}
+
+/// `POST`, `PUT` and `PATCH` requests, optionally with a JSON body.
+pub struct WithBody<'b, B = ()> {
+    url: String,
+    body: Option<&'b B>,
+}
+
+/// States a [`ServiceRequest`] can be sent in.
+pub trait Addressed {
+    fn url(&self) -> &str;
+    fn body(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder;
+}
+
+impl Addressed for Bodiless {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn body(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request
+    }
+}
+
+impl<B: Serialize> Addressed for WithBody<'_, B> {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn body(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.body {
+            Some(body) => request.json(body),
+            None => request,
+        }
+    }
+}
+
+/// Request to another service, authenticated as `auth`.
+///
+/// The method is chosen with `get`, `post`, `put`, `patch` or `delete`, and only requests
+/// that have one can be sent. `json` is only available for methods that take a body.
+pub struct ServiceRequest<'a, S = Unaddressed> {
     client: &'a reqwest::Client,
     method: reqwest::Method,
-    url: Option<String>,
-    body: Option<&'b T>,
+    state: S,
+    query: Vec<(String, String)>,
+    headers: HeaderMap,
     auth: Auth,
+    on_behalf_of: Option<String>,
+    idempotent: bool,
 }
 
-impl<'a, 'b, T: Serialize> ServiceRequest<'a, 'b, T> {
+impl<'a> ServiceRequest<'a> {
     pub fn new(client: &'a reqwest::Client, auth: Auth) -> Self {
         Self {
             client,
             auth,
             method: reqwest::Method::GET,
-            url: None,
-            body: None,
+            state: Unaddressed,
+            query: Vec::new(),
+            headers: HeaderMap::new(),
+            on_behalf_of: None,
+            idempotent: false,
+        }
+    }
+
+    fn address<S>(self, method: reqwest::Method, state: S) -> ServiceRequest<'a, S> {
+        ServiceRequest {
+            client: self.client,
+            method,
+            state,
+            query: self.query,
+            headers: self.headers,
+            auth: self.auth,
+            on_behalf_of: self.on_behalf_of,
+            idempotent: self.idempotent,
         }
     }
 
-    pub fn get(mut self, url: String) -> Self {
-        self.url = Some(url);
+    fn with_body(self, method: reqwest::Method, url: String) -> ServiceRequest<'a, WithBody<'a>> {
+        self.address(method, WithBody { url, body: None })
+    }
+
+    pub fn get(self, url: String) -> ServiceRequest<'a, Bodiless> {
+        self.address(reqwest::Method::GET, Bodiless { url })
+    }
+
+    pub fn delete(self, url: String) -> ServiceRequest<'a, Bodiless> {
+        self.address(reqwest::Method::DELETE, Bodiless { url })
+    }
+
+    pub fn post(self, url: String) -> ServiceRequest<'a, WithBody<'a>> {
+        self.with_body(reqwest::Method::POST, url)
+    }
+
+    pub fn put(self, url: String) -> ServiceRequest<'a, WithBody<'a>> {
+        self.with_body(reqwest::Method::PUT, url)
+    }
+
+    pub fn patch(self, url: String) -> ServiceRequest<'a, WithBody<'a>> {
+        self.with_body(reqwest::Method::PATCH, url)
+    }
+}
+
+impl<'a, 'b, B> ServiceRequest<'a, WithBody<'b, B>> {
+    pub fn json<'c, C: Serialize>(self, body: &'c C) -> ServiceRequest<'a, WithBody<'c, C>> {
+        let state = WithBody {
+            url: self.state.url,
+            body: Some(body),
+        };
+        ServiceRequest {
+            client: self.client,
+            method: self.method,
+            state,
+            query: self.query,
+            headers: self.headers,
+            auth: self.auth,
+            on_behalf_of: self.on_behalf_of,
+            idempotent: self.idempotent,
+        }
+    }
+}
+
+impl<'a, S> ServiceRequest<'a, S> {
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
         self
     }
 
-    pub fn post(mut self, url: String) -> Self {
-        self.url = Some(url);
-        self.method = reqwest::Method::POST;
+    /// Forwards the token of the end user the request is made for.
+    pub fn on_behalf_of(mut self, token: Option<String>) -> Self {
+        self.on_behalf_of = token;
         self
     }
 
-    pub fn patch(mut self, url: String) -> Self {
-        self.url = Some(url);
-        self.method = reqwest::Method::PATCH;
+    /// Marks a non-idempotent method, like a read-only `POST`, as safe to retry.
+    pub fn idempotent(mut self) -> Self {
+        self.idempotent = true;
         self
     }
 
-    pub fn delete(mut self, url: String) -> Self {
-        self.url = Some(url);
-        self.method = reqwest::Method::DELETE;
+    /// Appends `key=value` to the query string.
+    pub fn query(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.query.push((key.into(), value.to_string()));
         self
     }
 
-    pub fn json(mut self, body: &'b T) -> Self {
-        self.body = Some(body);
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
         self
     }
+}
 
-    pub async fn send(self) -> anyhow::Result<reqwest::Response> {
-        let url = self.url.as_ref().unwrap();
-        let mut request = self.client.request(self.method, url);
-        if let Some(body) = self.body {
-            request = request.json(body);
+impl<S: Addressed> ServiceRequest<'_, S> {
+    fn build(&self, url: &reqwest::Url, token: &str) -> reqwest::RequestBuilder {
+        let mut request = self
+            .client
+            .request(self.method.clone(), url.clone())
+            .query(&self.query)
+            .headers(self.headers.clone())
+            .bearer_auth(token);
+        if let Some(token) = &self.on_behalf_of {
+            request = request.header(ON_BEHALF_OF, token);
         }
-        let response = request.send().await?;
-        Ok(response)
+        self.state.body(request)
     }
 
-    pub fn auth(mut self, auth: Auth) -> Self {
-        self.auth = auth;
-        self
+    /// Sends the request with the timeout and retries configured for the target, failing
+    /// fast with `ServiceError::Upstream` while its circuit is open.
+    ///
+    /// Only idempotent requests are retried, after timeouts, connection errors and
+    /// 502/503/504 responses.
+    pub async fn send(self) -> anyhow::Result<reqwest::Response> {
+        let url = self.state.url();
+        let url = reqwest::Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+        let peer = peer_name(&url);
+        let policy = PEERS.policy(&peer);
+        let token = self.auth.to_token()?;
+        let retries = if self.idempotent || self.method.is_idempotent() {
+            policy.retries
+        } else {
+            0
+        };
+
+        let mut attempt = 0;
+        loop {
+            if !PEERS.acquire(&peer) {
+                return Err(ServiceError::Upstream(format!("{} is unavailable", peer)).into());
+            }
+
+            let result = self
+                .build(&url, &token)
+                .timeout(policy.timeout)
+                .send()
+                .await;
+            let failed = match &result {
+                Ok(response) => matches!(
+                    response.status(),
+                    StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                ),
+                Err(err) => err.is_timeout() || err.is_connect(),
+            };
+            if !failed {
+                PEERS.succeeded(&peer);
+                return Ok(result?);
+            }
+
+            PEERS.failed(&peer);
+            if attempt >= retries {
+                return Ok(result?);
+            }
+            attempt += 1;
+            PEERS.retried(&peer);
+            tokio::time::sleep(CallPolicy::backoff(attempt)).await;
+        }
     }
 }
 
@@ -141,14 +363,75 @@ impl Context {
         self.0.repositories.get::<Repository<T>>().cloned()
     }
 
-    pub fn make_request<T: Serialize>(&self) -> ServiceRequest<T> {
-        ServiceRequest::<T>::new(&self.0.client, self.0.auth.clone())
+    /// Repository of `T`, failing with `ServiceError::Internal` when the service has none.
+    pub fn repository<T: 'static>(&self) -> Result<Repository<T>, ServiceError> {
+        self.get_repository::<T>()
+            .ok_or_else(|| ServiceError::Internal(anyhow::anyhow!("Repository not found")))
+    }
+
+    /// Id of the end user the request comes from.
+    pub fn caller(&self) -> Result<ObjectId, ServiceError> {
+        caller_id(self.1.end_user())
+            .ok_or_else(|| ServiceError::Unauthorized("Authentication required".to_string()))
+    }
+
+    /// Context of a request by `user_auth` to the service with `state`.
+    #[cfg(test)]
+    pub(crate) fn test(state: impl Into<Arc<ServiceState>>, user_auth: Option<Auth>) -> Context {
+        Context(
+            state.into(),
+            HandlerContext {
+                user_auth,
+                on_behalf_of: None,
+                forwarded_token: None,
+            },
+        )
+    }
+
+    /// Context acting as this service, exempt from the caller's row level security.
+    pub fn privileged(&self) -> Context {
+        Context(
+            Arc::clone(&self.0),
+            HandlerContext {
+                user_auth: Some(self.0.auth.clone()),
+                on_behalf_of: None,
+                forwarded_token: None,
+            },
+        )
+    }
+
+    /// Request authenticated as this service, forwarding the end user of the current request.
+    pub fn make_request(&self) -> ServiceRequest<'_> {
+        ServiceRequest::new(&self.0.client, self.0.auth.clone())
+            .on_behalf_of(self.1.forwarded_token.clone())
+    }
+}
+
+/// How the end user relates to the entity being made public, deciding which
+/// `OptionallyPrivate` values they see. See `auth::policy::viewer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewer {
+    Anonymous,
+    /// Authenticated user without any relation to the entity.
+    User,
+    /// User on the other side of an active audit with the owner, see `Entity::is_counterpart`.
+    Counterpart,
+    Owner,
+    /// Admins and services acting on their own.
+    Admin,
+}
+
+impl Viewer {
+    pub fn sees_private(self) -> bool {
+        matches!(self, Viewer::Counterpart | Viewer::Owner | Viewer::Admin)
     }
 }
 
 pub struct MutationContext<'a> {
     pub context: &'a Context,
     pub current_field: Option<String>,
+    /// Viewer `Entity::to_public` makes the entity public for, `Anonymous` unless resolved.
+    pub viewer: Viewer,
 }
 
 impl<'a> MutationContext<'a> {
@@ -156,6 +439,55 @@ impl<'a> MutationContext<'a> {
         Self {
             current_field: None,
             context,
+            viewer: Viewer::Anonymous,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> ServiceRequest<'static> {
+        static CLIENT: once_cell::sync::Lazy<reqwest::Client> =
+            once_cell::sync::Lazy::new(reqwest::Client::new);
+        ServiceRequest::new(&CLIENT, Auth::Service("test".to_string()))
+    }
+
+    #[test]
+    fn builds_query_and_headers() {
+        let request = request()
+            .get("http://project/api/project/list".to_string())
+            .query("limit", 10)
+            .header(
+                HeaderName::from_static("x-request-id"),
+                HeaderValue::from_static("abc"),
+            )
+            .on_behalf_of(Some("user-token".to_string()));
+        let url = reqwest::Url::parse(request.state.url()).unwrap();
+        let built = request.build(&url, "token").build().unwrap();
+
+        assert_eq!(built.method(), reqwest::Method::GET);
+        assert_eq!(built.url().query(), Some("limit=10"));
+        assert_eq!(built.headers()["x-request-id"], "abc");
+        assert_eq!(built.headers()["authorization"], "Bearer token");
+        assert_eq!(built.headers()[ON_BEHALF_OF], "user-token");
+        assert!(built.body().is_none());
+    }
+
+    #[test]
+    fn serializes_json_body() {
+        let body = serde_json::json!({ "name": "audit" });
+        let request = request()
+            .post("http://project/api/project/insert".to_string())
+            .json(&body);
+        let url = reqwest::Url::parse(request.state.url()).unwrap();
+        let built = request.build(&url, "token").build().unwrap();
+
+        assert_eq!(built.method(), reqwest::Method::POST);
+        assert_eq!(
+            built.body().and_then(|body| body.as_bytes()),
+            Some(br#"{"name":"audit"}"#.as_slice())
+        );
+    }
+}
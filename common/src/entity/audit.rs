@@ -1,24 +1,213 @@
 use std::collections::HashMap;
 
 use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
 
-use super::audit_request::TimeRange;
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    error::ServiceError,
+    repository::Method,
+};
 
+use super::{
+    audit_request::{Party, TimeRange},
+    status::Lifecycle,
+    value_entity, Entity,
+};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    Pending,
+    InProgress,
+    ReportSubmitted,
+    Resolved,
+}
+
+impl Lifecycle for AuditStatus {
+    const TRANSITIONS: &'static [(Self, Self, Party)] = &[
+        (
+            AuditStatus::Pending,
+            AuditStatus::InProgress,
+            Party::Auditor,
+        ),
+        (
+            AuditStatus::InProgress,
+            AuditStatus::ReportSubmitted,
+            Party::Auditor,
+        ),
+        // The customer asks for changes to the report.
+        (
+            AuditStatus::ReportSubmitted,
+            AuditStatus::InProgress,
+            Party::Customer,
+        ),
+        (
+            AuditStatus::ReportSubmitted,
+            AuditStatus::Resolved,
+            Party::Customer,
+        ),
+    ];
+}
+
+value_entity!(AuditStatus);
+
+/// Entry of `Audit::history`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Entity)]
+#[entity(root = "Audit")]
+pub struct Transition {
+    pub from: AuditStatus,
+    pub to: AuditStatus,
+    pub by: ObjectId,
+    pub time: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "audit")]
 pub struct Audit {
+    #[serde(rename = "_id")]
     pub id: ObjectId,
+    #[entity(owner)]
     pub customer_id: ObjectId,
+    #[entity(owner)]
     pub auditor_id: ObjectId,
     pub project_id: ObjectId,
     pub auditor_contacts: HashMap<String, String>,
     pub customer_contacts: HashMap<String, String>,
     pub avatar: String,
     pub description: String,
-    pub status: String,
+    pub status: AuditStatus,
     pub scope: Vec<String>,
     pub price: String,
     pub report_link: Option<String>,
     pub tags: Vec<String>,
     pub time: TimeRange,
     pub time_frame: String,
+    pub history: Vec<Transition>,
     pub last_modified: i64,
 }
+
+impl Audit {
+    /// Moves the audit to `status` on behalf of `user`, recording the transition.
+    pub fn transition(
+        &mut self,
+        status: AuditStatus,
+        user: ObjectId,
+        now: i64,
+    ) -> Result<(), ServiceError> {
+        let party = if user == self.customer_id {
+            Party::Customer
+        } else if user == self.auditor_id {
+            Party::Auditor
+        } else {
+            return Err(ServiceError::Forbidden(
+                "Only the customer and the auditor can change the status".to_string(),
+            ));
+        };
+        self.status.check_transition(status, party)?;
+
+        self.history.push(Transition {
+            from: self.status,
+            to: status,
+            by: user,
+            time: now,
+        });
+        self.status = status;
+        self.last_modified = now;
+        Ok(())
+    }
+}
+
+impl Policy for Audit {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            (Some(Auth::User(_)), Method::Find | Method::FindByDoc | Method::FindMany) => {
+                Access::Owner
+            }
+            // Audits are created by accepting an audit request.
+            _ => Access::Deny,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        entity::audit_request::{AuditRequest, Offer},
+        error::ErrorCode,
+    };
+
+    #[test]
+    fn validates_audit_status_transitions() {
+        let mut request = AuditRequest::new(
+            ObjectId::new(),
+            ObjectId::new(),
+            ObjectId::new(),
+            Party::Auditor,
+            Offer {
+                price: Some("100".to_string()),
+                ..Default::default()
+            },
+            1,
+        );
+        let mut audit = request.accept(Party::Customer, 2).unwrap();
+        let (customer, auditor) = (audit.customer_id, audit.auditor_id);
+        assert_eq!(audit.status, AuditStatus::Pending);
+
+        for (status, user, error) in [
+            (AuditStatus::Resolved, auditor, Some(ErrorCode::Conflict)),
+            (
+                AuditStatus::InProgress,
+                customer,
+                Some(ErrorCode::Forbidden),
+            ),
+            (
+                AuditStatus::InProgress,
+                ObjectId::new(),
+                Some(ErrorCode::Forbidden),
+            ),
+            (AuditStatus::InProgress, auditor, None),
+            (AuditStatus::ReportSubmitted, auditor, None),
+            (AuditStatus::Resolved, customer, None),
+            (AuditStatus::InProgress, customer, Some(ErrorCode::Conflict)),
+        ] {
+            let result = audit.transition(status, user, 3);
+            assert_eq!(result.err().map(|err| err.code()), error, "{:?}", status);
+        }
+
+        assert_eq!(audit.status, AuditStatus::Resolved);
+        let history: Vec<_> = audit
+            .history
+            .iter()
+            .map(|transition| (transition.from, transition.to, transition.by))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                (AuditStatus::Pending, AuditStatus::InProgress, auditor),
+                (
+                    AuditStatus::InProgress,
+                    AuditStatus::ReportSubmitted,
+                    auditor
+                ),
+                (
+                    AuditStatus::ReportSubmitted,
+                    AuditStatus::Resolved,
+                    customer
+                ),
+            ]
+        );
+        assert_eq!(
+            AuditStatus::Pending
+                .check_transition(AuditStatus::Resolved, Party::Customer)
+                .unwrap_err()
+                .to_string(),
+            "Status can't change from `pending` to `resolved`"
+        );
+    }
+}
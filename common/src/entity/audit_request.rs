@@ -1,24 +1,95 @@
 use std::collections::HashMap;
 
-use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, oid::ObjectId, Document};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    context::Context,
+    error::ServiceError,
+    repository::{Method, ReadRepositoryTrait},
+};
+
+use super::{
+    audit::{Audit, AuditStatus},
+    value_entity, Entity,
+};
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct PriceRange {
     pub lower_bound: String,
     pub upper_bound: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
 pub struct TimeRange {
     pub begin: String,
     pub end: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+/// Side of an audit request.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Party {
+    Customer,
+    Auditor,
+}
+
+impl Party {
+    pub fn other(self) -> Party {
+        match self {
+            Party::Customer => Party::Auditor,
+            Party::Auditor => Party::Customer,
+        }
+    }
+
+    /// Name of the party, as it is serialized.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Party::Customer => "customer",
+            Party::Auditor => "auditor",
+        }
+    }
+}
+
+value_entity!(PriceRange, TimeRange, Party);
+
+/// Terms a party proposes. Fields left out keep their current value.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct Offer {
+    pub description: Option<String>,
+    pub scope: Option<Vec<String>>,
+    pub price: Option<String>,
+    pub time_frame: Option<String>,
+    pub time: Option<TimeRange>,
+    /// Contacts of the party making the offer.
+    pub contacts: Option<HashMap<String, String>>,
+}
+
+/// Entry of `AuditRequest::changes`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Entity)]
+#[entity(root = "AuditRequest")]
+pub struct Change {
+    pub by: Party,
+    pub fields: Vec<String>,
+    pub time: i64,
+}
+
+/// Negotiation of an audit between a customer and an auditor.
+///
+/// The parties take turns: each offer is answered by the other party, either with a
+/// counter-offer or by accepting it, which turns the request into an `Audit`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Entity)]
+#[entity(name = "audit_request")]
 pub struct AuditRequest {
+    #[serde(rename = "_id")]
     pub id: ObjectId,
+    #[entity(owner)]
     pub auditor_id: ObjectId,
+    #[entity(owner)]
     pub customer_id: ObjectId,
     pub project_id: ObjectId,
     pub auditor_contacts: HashMap<String, String>,
@@ -28,7 +99,294 @@ pub struct AuditRequest {
     pub scope: Vec<String>,
     pub price: Option<String>,
     pub time_frame: String,
-    pub last_changer: String,
+    /// Party whose offer awaits an answer.
+    pub last_changer: Party,
     pub time: TimeRange,
+    pub changes: Vec<Change>,
+    /// Set once the last offer is accepted, until the request is replaced by its audit.
+    #[serde(default)]
+    pub accepted: bool,
     pub last_modified: i64,
 }
+
+impl AuditRequest {
+    /// Request opened by `creator` with the terms of `offer`.
+    pub fn new(
+        auditor_id: ObjectId,
+        customer_id: ObjectId,
+        project_id: ObjectId,
+        creator: Party,
+        offer: Offer,
+        now: i64,
+    ) -> Self {
+        let mut request = Self {
+            id: ObjectId::new(),
+            auditor_id,
+            customer_id,
+            project_id,
+            auditor_contacts: HashMap::new(),
+            customer_contacts: HashMap::new(),
+            avatar: String::new(),
+            description: None,
+            scope: Vec::new(),
+            price: None,
+            time_frame: String::new(),
+            last_changer: creator,
+            time: TimeRange::default(),
+            changes: Vec::new(),
+            accepted: false,
+            last_modified: now,
+        };
+        let fields = request.apply(creator, offer);
+        request.changes.push(Change {
+            by: creator,
+            fields,
+            time: now,
+        });
+        request
+    }
+
+    /// Side `user` is on, if any.
+    pub fn party_of(&self, user: ObjectId) -> Option<Party> {
+        if user == self.customer_id {
+            Some(Party::Customer)
+        } else if user == self.auditor_id {
+            Some(Party::Auditor)
+        } else {
+            None
+        }
+    }
+
+    /// Answers the pending offer of the other party with `offer`.
+    pub fn counter(&mut self, party: Party, offer: Offer, now: i64) -> Result<(), ServiceError> {
+        self.check_turn(party)?;
+        let fields = self.apply(party, offer);
+        if fields.is_empty() {
+            return Err(ServiceError::BadRequest(
+                "Offer doesn't change the request".to_string(),
+            ));
+        }
+
+        self.changes.push(Change {
+            by: party,
+            fields,
+            time: now,
+        });
+        self.last_changer = party;
+        self.last_modified = now;
+        Ok(())
+    }
+
+    /// Filter matching this request only while nobody answered it since it was read.
+    pub fn revision(&self) -> Document {
+        doc! {
+            "last_changer": self.last_changer.as_str(),
+            "changes": { "$size": self.changes.len() as i64 },
+            "accepted": self.accepted,
+        }
+    }
+
+    /// Accepts the pending offer of the other party, agreeing on the audit it describes.
+    pub fn accept(&mut self, party: Party, now: i64) -> Result<Audit, ServiceError> {
+        self.check_turn(party)?;
+        let audit = self.to_audit(now)?;
+        self.accepted = true;
+        self.last_modified = now;
+        Ok(audit)
+    }
+
+    /// Audit on the terms of the request.
+    pub fn to_audit(&self, now: i64) -> Result<Audit, ServiceError> {
+        let Some(price) = self.price.clone() else {
+            return Err(ServiceError::BadRequest(
+                "Price must be offered before accepting".to_string(),
+            ));
+        };
+
+        Ok(Audit {
+            // Shared with the request, so converting it twice conflicts.
+            id: self.id,
+            customer_id: self.customer_id,
+            auditor_id: self.auditor_id,
+            project_id: self.project_id,
+            auditor_contacts: self.auditor_contacts.clone(),
+            customer_contacts: self.customer_contacts.clone(),
+            avatar: self.avatar.clone(),
+            description: self.description.clone().unwrap_or_default(),
+            status: AuditStatus::Pending,
+            scope: self.scope.clone(),
+            price,
+            report_link: None,
+            tags: Vec::new(),
+            time: self.time.clone(),
+            time_frame: self.time_frame.clone(),
+            history: Vec::new(),
+            last_modified: now,
+        })
+    }
+
+    fn check_turn(&self, party: Party) -> Result<(), ServiceError> {
+        if self.accepted {
+            return Err(ServiceError::Conflict(
+                "The request is already accepted".to_string(),
+            ));
+        }
+        if party == self.last_changer {
+            return Err(ServiceError::Conflict(format!(
+                "Waiting for the {} to answer the last offer",
+                party.other().as_str()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Applies `offer`, returning the names of the fields it changed.
+    fn apply(&mut self, party: Party, offer: Offer) -> Vec<String> {
+        let mut fields = Vec::new();
+        let mut set = |field: &str, changed: bool| {
+            if changed {
+                fields.push(field.to_string());
+            }
+        };
+
+        if let Some(description) = offer.description {
+            set(
+                "description",
+                self.description.as_ref() != Some(&description),
+            );
+            self.description = Some(description);
+        }
+        if let Some(scope) = offer.scope {
+            set("scope", self.scope != scope);
+            self.scope = scope;
+        }
+        if let Some(price) = offer.price {
+            set("price", self.price.as_ref() != Some(&price));
+            self.price = Some(price);
+        }
+        if let Some(time_frame) = offer.time_frame {
+            set("time_frame", self.time_frame != time_frame);
+            self.time_frame = time_frame;
+        }
+        if let Some(time) = offer.time {
+            set("time", self.time != time);
+            self.time = time;
+        }
+        if let Some(contacts) = offer.contacts {
+            let (field, current) = match party {
+                Party::Customer => ("customer_contacts", &mut self.customer_contacts),
+                Party::Auditor => ("auditor_contacts", &mut self.auditor_contacts),
+            };
+            set(field, *current != contacts);
+            *current = contacts;
+        }
+        fields
+    }
+}
+
+impl Policy for AuditRequest {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            // Offers go through `/api/audit_request/:id/offer` to keep the parties in turn.
+            (Some(Auth::User(_)), Method::Insert | Method::Update) => Access::Deny,
+            (Some(Auth::User(_)), _) => Access::Owner,
+            _ => Access::Deny,
+        }
+    }
+}
+
+/// Whether `auditor` and `customer` have an audit request or an audit together, as far as
+/// the repositories of this service tell.
+pub async fn are_counterparts(
+    auditor: ObjectId,
+    customer: ObjectId,
+    context: &Context,
+) -> anyhow::Result<bool> {
+    let filter = doc! { "auditor_id": auditor, "customer_id": customer };
+    if let Some(requests) = context.get_repository::<AuditRequest>() {
+        if requests
+            .find_by_doc(filter.clone(), context)
+            .await?
+            .is_some()
+        {
+            return Ok(true);
+        }
+    }
+    if let Some(audits) = context.get_repository::<Audit>() {
+        if audits.find_by_doc(filter, context).await?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn negotiates_audit_requests_in_turns() {
+        let offer = |price: &str| Offer {
+            price: Some(price.to_string()),
+            ..Default::default()
+        };
+        let mut request = AuditRequest::new(
+            ObjectId::new(),
+            ObjectId::new(),
+            ObjectId::new(),
+            Party::Customer,
+            Offer {
+                scope: Some(vec!["contracts/".to_string()]),
+                ..offer("100")
+            },
+            1,
+        );
+        assert_eq!(request.party_of(request.auditor_id), Some(Party::Auditor));
+        assert_eq!(request.party_of(ObjectId::new()), None);
+
+        let err = request
+            .counter(Party::Customer, offer("90"), 2)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        assert!(request.accept(Party::Customer, 2).is_err());
+        let err = request
+            .counter(Party::Auditor, offer("100"), 2)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::BadRequest);
+
+        request.counter(Party::Auditor, offer("150"), 3).unwrap();
+        assert_eq!(request.last_changer, Party::Auditor);
+        assert_eq!(
+            request.changes,
+            vec![
+                Change {
+                    by: Party::Customer,
+                    fields: vec!["scope".to_string(), "price".to_string()],
+                    time: 1,
+                },
+                Change {
+                    by: Party::Auditor,
+                    fields: vec!["price".to_string()],
+                    time: 3,
+                },
+            ]
+        );
+
+        let revision = request.revision();
+        let audit = request.accept(Party::Customer, 4).unwrap();
+        assert_eq!(audit.id, request.id);
+        assert_eq!(audit.price, "150");
+        assert_eq!(audit.scope, vec!["contracts/".to_string()]);
+        assert_ne!(request.revision(), revision);
+        assert_eq!(request.to_audit(4).unwrap().price, audit.price);
+
+        let err = request
+            .counter(Party::Auditor, offer("200"), 5)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+        let err = request.accept(Party::Customer, 5).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+    }
+}
@@ -1,10 +1,24 @@
 use std::collections::HashMap;
 
 use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
 
-use super::OptionallyPrivate;
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    context::Context,
+    repository::Method,
+};
 
+use super::{audit_request::are_counterparts, Entity, OptionallyPrivate};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "auditor", counterpart = "is_counterpart")]
 pub struct Auditor {
+    #[serde(rename = "_id")]
+    #[entity(owner)]
     pub id: ObjectId,
     pub avatar: String,
     pub first_name: String,
@@ -17,3 +31,23 @@ pub struct Auditor {
     pub free_at: String,
     pub last_modified: i64,
 }
+
+impl Policy for Auditor {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            // Profiles of others are read through `/api/auditor/profile/:id`.
+            (Some(Auth::User(_)), _) => Access::Owner,
+            _ => Access::Deny,
+        }
+    }
+}
+
+/// Customers working with the auditor see their private contacts.
+async fn is_counterpart(
+    auditor: &Auditor,
+    user: ObjectId,
+    context: &Context,
+) -> anyhow::Result<bool> {
+    are_counterparts(auditor.id, user, context).await
+}
@@ -1,10 +1,24 @@
 use std::collections::HashMap;
 
 use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
 
-use super::OptionallyPrivate;
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    context::Context,
+    repository::Method,
+};
 
+use super::{audit_request::are_counterparts, Entity, OptionallyPrivate};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "customer", counterpart = "is_counterpart")]
 pub struct Customer {
+    #[serde(rename = "_id")]
+    #[entity(owner)]
     pub id: ObjectId,
     pub avatar: String,
     pub first_name: String,
@@ -15,3 +29,23 @@ pub struct Customer {
     pub tags: Vec<String>,
     pub last_modified: i64,
 }
+
+impl Policy for Customer {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            // Profiles of others are read through `/api/customer/profile/:id`.
+            (Some(Auth::User(_)), _) => Access::Owner,
+            _ => Access::Deny,
+        }
+    }
+}
+
+/// Auditors working with the customer see their private contacts.
+async fn is_counterpart(
+    customer: &Customer,
+    user: ObjectId,
+    context: &Context,
+) -> anyhow::Result<bool> {
+    are_counterparts(user, customer.id, context).await
+}
@@ -3,33 +3,114 @@ pub mod audit_request;
 pub mod auditor;
 pub mod customer;
 pub mod project;
+pub mod status;
 pub mod user;
 
+use std::collections::HashMap;
+
 use axum::async_trait;
 use mongodb::bson::{doc, oid::ObjectId, Bson};
 use serde::{Deserialize, Serialize};
 
-use crate::{context::MutationContext, repository::Method};
+use crate::{
+    context::{Context, MutationContext},
+    error::ServiceError,
+    repository::Method,
+};
+
+pub use entity_derive::Entity;
+
+/// Dotted path of the field `key` below `parent`, as used in Mongo queries.
+pub fn field_path(parent: Option<&str>, key: &str) -> String {
+    match parent {
+        Some(parent) => format!("{}.{}", parent, key),
+        None => key.to_string(),
+    }
+}
+
+/// Outcome of an `Entity` hook, with the same meaning for every `Method`.
+#[derive(Debug)]
+pub enum HookResult {
+    Continue,
+    /// Stops the operation, failing it with the given error. Aborting after a write
+    /// doesn't undo it.
+    Abort(ServiceError),
+    /// Leaves the entity out of the result. Writes still happen.
+    Hide,
+}
+
+impl HookResult {
+    pub fn conflict(message: impl Into<String>) -> Self {
+        HookResult::Abort(ServiceError::Conflict(message.into()))
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        HookResult::Abort(ServiceError::BadRequest(message.into()))
+    }
+
+    /// Combines the results of hooks on several fields: aborting wins over hiding.
+    pub fn and(self, other: HookResult) -> HookResult {
+        match (self, other) {
+            (HookResult::Abort(err), _) | (_, HookResult::Abort(err)) => HookResult::Abort(err),
+            (HookResult::Hide, _) | (_, HookResult::Hide) => HookResult::Hide,
+            _ => HookResult::Continue,
+        }
+    }
+
+    /// Fails with the reason of an abort, otherwise whether the entity stays visible.
+    pub fn visible(self) -> Result<bool, ServiceError> {
+        match self {
+            HookResult::Continue => Ok(true),
+            HookResult::Hide => Ok(false),
+            HookResult::Abort(err) => Err(err),
+        }
+    }
+}
 
 #[async_trait]
 pub trait Entity<RootRef> {
-    type PublicEntity;
+    /// What callers other than services get to see of the entity, see `to_public`.
+    type PublicEntity: Serialize + Send;
 
     const NAME: &'static str;
 
+    /// Fields holding the ids of the users owning a document. Queries of `Auth::User`
+    /// callers are scoped to these fields, see `auth::policy::scope_filter`.
+    const OWNER_FIELDS: &'static [&'static str] = &[];
+
     fn to_public(&self, context: &mut MutationContext) -> Self::PublicEntity;
 
+    /// Collects the paths of the `Unique` values at or below `path`, which repositories
+    /// back with unique indexes.
+    fn unique_fields(_path: Option<&str>, _fields: &mut Vec<String>)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Runs before `Insert`, `Update` and `Delete`, on the entity as it will be stored
+    /// or, for `Delete`, as it is.
     async fn before_execution(
         &self,
         _: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool>;
+    ) -> anyhow::Result<HookResult>;
 
+    /// Runs on every entity a repository read or wrote, before it is returned.
     async fn after_execution(
         &self,
         _: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool>;
+    ) -> anyhow::Result<HookResult>;
+
+    /// Whether `user`, who doesn't own the entity, is on the other side of an active audit
+    /// with its owner, which lets them see `OptionallyPrivate` values.
+    async fn is_counterpart(&self, _user: ObjectId, _context: &Context) -> anyhow::Result<bool>
+    where
+        Self: Sync,
+    {
+        Ok(false)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,31 +128,42 @@ impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Clone + Sync> Entity<RootRef
     const NAME: &'static str = RootRef::NAME;
 
     fn to_public(&self, ctx: &mut MutationContext) -> Self::PublicEntity {
-        if self.is_private {
-            Some(self.value.to_public(ctx))
-        } else {
-            None
+        if self.is_private && !ctx.viewer.sees_private() {
+            return None;
         }
+        Some(self.value.to_public(ctx))
+    }
+    fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+        T::unique_fields(Some(&field_path(path, "value")), fields);
     }
+
     async fn before_execution(
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
-        let res = self.value.before_execution(ctx, method);
-        res.await
+    ) -> anyhow::Result<HookResult> {
+        let parent = ctx.current_field.clone();
+        ctx.current_field = Some(field_path(parent.as_deref(), "value"));
+        let res = self.value.before_execution(ctx, method).await;
+        ctx.current_field = parent;
+        res
     }
     async fn after_execution(
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
-        let res = self.value.after_execution(ctx, method);
-        res.await
+    ) -> anyhow::Result<HookResult> {
+        let parent = ctx.current_field.clone();
+        ctx.current_field = Some(field_path(parent.as_deref(), "value"));
+        let res = self.value.after_execution(ctx, method).await;
+        ctx.current_field = parent;
+        res
     }
 }
 
+/// Stored as the plain value, but left out of the public entity.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Private<T> {
     pub value: T,
 }
@@ -88,15 +180,17 @@ impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Clone + Sync> Entity<RootRef
 
     const NAME: &'static str = RootRef::NAME;
 
-    fn to_public(&self, _: &mut MutationContext) -> Self::PublicEntity {
-        ()
+    fn to_public(&self, _: &mut MutationContext) -> Self::PublicEntity {}
+
+    fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+        T::unique_fields(path, fields);
     }
 
     async fn before_execution(
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
+    ) -> anyhow::Result<HookResult> {
         let res = self.value.before_execution(ctx, method);
         res.await
     }
@@ -104,7 +198,7 @@ impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Clone + Sync> Entity<RootRef
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
+    ) -> anyhow::Result<HookResult> {
         let res = self.value.after_execution(ctx, method);
         res.await
     }
@@ -115,7 +209,9 @@ pub struct InsertCondition<T> {
     pub value: T,
 }
 
+/// Stored as the plain value, so documents can be queried by it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Unique<T> {
     pub value: T,
 }
@@ -141,18 +237,19 @@ where
         self.value.to_public(ctx)
     }
 
+    fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+        fields.extend(path.map(str::to_string));
+        T::unique_fields(path, fields);
+    }
+
     async fn before_execution(
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
-        let abort_future = self.value.before_execution(ctx, method);
-        let abort = abort_future.await?;
-        if abort {
-            return Ok(true);
-        }
-        if method != Method::Insert {
-            return Ok(false);
+    ) -> anyhow::Result<HookResult> {
+        let result = self.value.before_execution(ctx, method).await?;
+        if matches!(result, HookResult::Abort(_)) || method != Method::Insert {
+            return Ok(result);
         }
 
         let Some(repository) = ctx.context.get_repository::<RootRef>() else {
@@ -163,20 +260,26 @@ where
             anyhow::bail!("No field found");
         };
 
+        // Uniqueness spans all documents, not only the ones visible to the caller.
+        let context = ctx.context.privileged();
         let entity_future = repository
             .0
-            .find_by_doc(doc! { field: self.value.clone() }, ctx.context);
-
-        let entity = entity_future.await?;
+            .find_by_doc(doc! { field: self.value.clone() }, &context);
 
-        Ok(entity.is_none())
+        if entity_future.await?.is_some() {
+            return Ok(HookResult::conflict(format!(
+                "`{}` is already taken",
+                field
+            )));
+        }
+        Ok(result)
     }
 
     async fn after_execution(
         &self,
         ctx: &mut MutationContext,
         method: Method,
-    ) -> anyhow::Result<bool> {
+    ) -> anyhow::Result<HookResult> {
         let res = self.value.after_execution(ctx, method);
         res.await
     }
@@ -192,10 +295,363 @@ impl<RootRef: Entity<RootRef>> Entity<RootRef> for ObjectId {
         self.to_hex()
     }
 
-    async fn before_execution(&self, _: &mut MutationContext, _: Method) -> anyhow::Result<bool> {
-        Ok(false)
+    async fn before_execution(
+        &self,
+        _: &mut MutationContext,
+        _: Method,
+    ) -> anyhow::Result<HookResult> {
+        Ok(HookResult::Continue)
+    }
+    async fn after_execution(
+        &self,
+        _: &mut MutationContext,
+        _: Method,
+    ) -> anyhow::Result<HookResult> {
+        Ok(HookResult::Continue)
     }
-    async fn after_execution(&self, _: &mut MutationContext, _: Method) -> anyhow::Result<bool> {
-        Ok(true)
+}
+
+/// Plain values without hooks, public as they are. Domain values call it next to their
+/// definition.
+macro_rules! value_entity {
+    ($($ty:ty),*) => {$(
+        #[$crate::__private::async_trait]
+        impl<RootRef: $crate::entity::Entity<RootRef>> $crate::entity::Entity<RootRef> for $ty {
+            type PublicEntity = $ty;
+
+            const NAME: &'static str = RootRef::NAME;
+
+            fn to_public(&self, _: &mut $crate::context::MutationContext) -> Self::PublicEntity {
+                self.clone()
+            }
+
+            async fn before_execution(
+                &self,
+                _: &mut $crate::context::MutationContext,
+                _: $crate::repository::Method,
+            ) -> $crate::__private::anyhow::Result<$crate::entity::HookResult> {
+                Ok($crate::entity::HookResult::Continue)
+            }
+
+            async fn after_execution(
+                &self,
+                _: &mut $crate::context::MutationContext,
+                _: $crate::repository::Method,
+            ) -> $crate::__private::anyhow::Result<$crate::entity::HookResult> {
+                Ok($crate::entity::HookResult::Continue)
+            }
+        }
+    )*};
+}
+pub(crate) use value_entity;
+
+value_entity!(String, bool, i32, i64, u32, u64, f64);
+
+#[async_trait]
+impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Sync> Entity<RootRef> for Option<T> {
+    type PublicEntity = Option<T::PublicEntity>;
+
+    const NAME: &'static str = RootRef::NAME;
+
+    fn to_public(&self, ctx: &mut MutationContext) -> Self::PublicEntity {
+        self.as_ref().map(|value| value.to_public(ctx))
+    }
+
+    fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+        T::unique_fields(path, fields);
+    }
+
+    async fn before_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        match self {
+            Some(value) => value.before_execution(ctx, method).await,
+            None => Ok(HookResult::Continue),
+        }
+    }
+
+    async fn after_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        match self {
+            Some(value) => value.after_execution(ctx, method).await,
+            None => Ok(HookResult::Continue),
+        }
+    }
+}
+
+#[async_trait]
+impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Sync> Entity<RootRef> for Vec<T> {
+    type PublicEntity = Vec<T::PublicEntity>;
+
+    const NAME: &'static str = RootRef::NAME;
+
+    fn to_public(&self, ctx: &mut MutationContext) -> Self::PublicEntity {
+        self.iter().map(|value| value.to_public(ctx)).collect()
+    }
+
+    /// Elements share the path of the array, as in Mongo queries and indexes.
+    fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+        T::unique_fields(path, fields);
+    }
+
+    async fn before_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        let mut result = HookResult::Continue;
+        for value in self {
+            result = result.and(value.before_execution(ctx, method).await?);
+            if matches!(result, HookResult::Abort(_)) {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    async fn after_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        let mut result = HookResult::Continue;
+        for value in self {
+            result = result.and(value.after_execution(ctx, method).await?);
+            if matches!(result, HookResult::Abort(_)) {
+                break;
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl<RootRef: Entity<RootRef>, T: Entity<RootRef> + Sync> Entity<RootRef> for HashMap<String, T> {
+    type PublicEntity = HashMap<String, T::PublicEntity>;
+
+    const NAME: &'static str = RootRef::NAME;
+
+    // Keys are only known per document, so `Unique` values in maps get no backing index.
+
+    fn to_public(&self, ctx: &mut MutationContext) -> Self::PublicEntity {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.to_public(ctx)))
+            .collect()
+    }
+
+    async fn before_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        let parent = ctx.current_field.clone();
+        let mut result = HookResult::Continue;
+        for (key, value) in self {
+            ctx.current_field = Some(field_path(parent.as_deref(), key));
+            result = result.and(value.before_execution(ctx, method).await?);
+            if matches!(result, HookResult::Abort(_)) {
+                break;
+            }
+        }
+        ctx.current_field = parent;
+        Ok(result)
+    }
+
+    async fn after_execution(
+        &self,
+        ctx: &mut MutationContext,
+        method: Method,
+    ) -> anyhow::Result<HookResult> {
+        let parent = ctx.current_field.clone();
+        let mut result = HookResult::Continue;
+        for (key, value) in self {
+            ctx.current_field = Some(field_path(parent.as_deref(), key));
+            result = result.and(value.after_execution(ctx, method).await?);
+            if matches!(result, HookResult::Abort(_)) {
+                break;
+            }
+        }
+        ctx.current_field = parent;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        auth::{policy::viewer, Auth},
+        context::{Context, ServiceState, Viewer},
+        entity::{
+            audit_request::{AuditRequest, Offer, Party},
+            customer::Customer,
+        },
+        error::ErrorCode,
+        repository::{memory::InMemoryRepository, Repository, RepositoryTrait},
+    };
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+    #[entity(name = "account")]
+    struct Account {
+        #[serde(rename = "_id")]
+        #[entity(owner)]
+        id: ObjectId,
+        login: Unique<String>,
+        password: Private<String>,
+        profile: Profile,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+    #[entity(root = "Account")]
+    struct Profile {
+        handle: Unique<String>,
+        contacts: HashMap<String, OptionallyPrivate<String>>,
+    }
+
+    fn account(login: &str, handle: &str) -> Account {
+        Account {
+            id: ObjectId::new(),
+            login: Unique::new(login.to_string()),
+            password: Private::new("secret".to_string()),
+            profile: Profile {
+                handle: Unique::new(handle.to_string()),
+                contacts: HashMap::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn derives_public_entity() {
+        let context = Context::test(ServiceState::new("test".to_string()), None);
+        let account = account("alice", "al");
+        let public = account.to_public(&mut MutationContext::new(&context));
+
+        assert_eq!(Account::NAME, "account");
+        assert_eq!(Account::OWNER_FIELDS, &["_id"]);
+        assert_eq!(public.id, account.id.to_hex());
+        assert_eq!(public.login, "alice");
+        assert_eq!(public.profile.handle, "al");
+    }
+
+    #[test]
+    fn collects_unique_paths() {
+        let mut fields = Vec::new();
+        Account::unique_fields(None, &mut fields);
+        assert_eq!(fields, vec!["login", "profile.handle"]);
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+    #[entity(name = "badge")]
+    #[serde(deny_unknown_fields)]
+    struct Badge {
+        #[serde(default = "ObjectId::new", rename = "_id")]
+        #[entity(owner)]
+        id: ObjectId,
+        #[serde(skip_serializing_if = "Option::is_none", rename(serialize = "tag"))]
+        label: Option<String>,
+        #[serde(alias = "serial", rename = "code")]
+        serial: Unique<String>,
+    }
+
+    #[test]
+    fn reads_renames_from_multi_item_serde_attributes() {
+        assert_eq!(Badge::OWNER_FIELDS, &["_id"]);
+
+        let mut fields = Vec::new();
+        Badge::unique_fields(None, &mut fields);
+        assert_eq!(fields, vec!["code"]);
+
+        let context = Context::test(ServiceState::new("test".to_string()), None);
+        let badge = Badge {
+            id: ObjectId::new(),
+            label: Some("gold".to_string()),
+            serial: Unique::new("b-1".to_string()),
+        };
+        let public = badge.to_public(&mut MutationContext::new(&context));
+        let public = serde_json::to_value(public).unwrap();
+        assert_eq!(public["_id"], badge.id.to_hex());
+        assert_eq!(public["tag"], "gold");
+        assert_eq!(public["code"], "b-1");
+    }
+
+    #[tokio::test]
+    async fn enforces_unique_fields() {
+        let repository = Repository::<Account>(Arc::new(InMemoryRepository::new()));
+        let mut state = ServiceState::new("test".to_string());
+        state.insert(repository.clone());
+        let context = Context::test(state, None);
+
+        for (login, handle, conflict) in [
+            ("alice", "al", None),
+            ("alice", "ally", Some("`login` is already taken")),
+            ("bob", "al", Some("`profile.handle` is already taken")),
+            ("bob", "bo", None),
+        ] {
+            let account = account(login, handle);
+            let result = repository.insert(&account, &context).await;
+            let error = result.err().map(ServiceError::from);
+            assert_eq!(
+                error.as_ref().map(ServiceError::code),
+                conflict.map(|_| ErrorCode::Conflict)
+            );
+            assert_eq!(error.map(|err| err.to_string()).as_deref(), conflict);
+        }
+    }
+
+    #[tokio::test]
+    async fn reveals_private_contacts_to_counterparts() {
+        let customer = Customer {
+            id: ObjectId::new(),
+            avatar: String::new(),
+            first_name: "Carol".to_string(),
+            second_name: String::new(),
+            about: String::new(),
+            company: String::new(),
+            contacts: HashMap::from([(
+                "telegram".to_string(),
+                OptionallyPrivate {
+                    is_private: true,
+                    value: "@carol".to_string(),
+                },
+            )]),
+            tags: Vec::new(),
+            last_modified: 0,
+        };
+        let auditor = ObjectId::new();
+        let request = AuditRequest::new(
+            auditor,
+            customer.id,
+            ObjectId::new(),
+            Party::Customer,
+            Offer::default(),
+            0,
+        );
+
+        let requests = Repository::<AuditRequest>(Arc::new(InMemoryRepository::new()));
+        let mut state = ServiceState::new("test".to_string());
+        state.insert(requests.clone());
+        let context = Context::test(state, Some(Auth::User(auditor)));
+        let telegram = |viewer| {
+            let mut context = MutationContext::new(&context);
+            context.viewer = viewer;
+            customer.to_public(&mut context).contacts["telegram"].clone()
+        };
+
+        assert_eq!(viewer(&customer, &context).await.unwrap(), Viewer::User);
+        assert_eq!(telegram(Viewer::User), None);
+
+        requests.insert(&request, &context).await.unwrap();
+        assert_eq!(
+            viewer(&customer, &context).await.unwrap(),
+            Viewer::Counterpart
+        );
+        assert_eq!(telegram(Viewer::Counterpart), Some("@carol".to_string()));
     }
 }
@@ -1,24 +1,139 @@
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    repository::Method,
+};
+
+use super::{audit_request::Party, status::Lifecycle, value_entity, Entity};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectStatus {
+    Draft,
+    Published,
+    Closed,
+}
+
+impl Lifecycle for ProjectStatus {
+    const TRANSITIONS: &'static [(Self, Self, Party)] = &[
+        (
+            ProjectStatus::Draft,
+            ProjectStatus::Published,
+            Party::Customer,
+        ),
+        (
+            ProjectStatus::Published,
+            ProjectStatus::Draft,
+            Party::Customer,
+        ),
+        (ProjectStatus::Draft, ProjectStatus::Closed, Party::Customer),
+        (
+            ProjectStatus::Published,
+            ProjectStatus::Closed,
+            Party::Customer,
+        ),
+    ];
+}
+
+value_entity!(ProjectStatus);
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(root = "Project")]
 pub struct PublishOptions {
     pub publish: bool,
-    pub prise_from: String,
-    pub prise_to: String,
+    /// Price window the customer is ready to pay, searched by `prise_from..=prise_to`.
+    pub prise_from: i64,
+    pub prise_to: i64,
     pub ready_to_wait: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "project")]
 pub struct Project {
     #[serde(rename = "_id")]
     pub id: ObjectId,
+    #[entity(owner)]
     pub customer_id: ObjectId,
     pub name: String,
     pub description: String,
     pub scope: Vec<String>,
     pub tags: Vec<String>,
     pub publish_options: PublishOptions,
-    pub status: String,
+    pub status: ProjectStatus,
     pub last_modified: i64,
 }
+
+impl Policy for Project {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            // Drafts are created and moved along `ProjectStatus` through the project routes.
+            (Some(Auth::User(_)), Method::Insert | Method::Update) => Access::Deny,
+            // Published projects of others are found through `/api/project/search`.
+            (Some(Auth::User(_)), _) => Access::Owner,
+            _ => Access::Deny,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn validates_project_status_transitions() {
+        for (from, to, party, error) in [
+            (
+                ProjectStatus::Draft,
+                ProjectStatus::Published,
+                Party::Customer,
+                None,
+            ),
+            (
+                ProjectStatus::Published,
+                ProjectStatus::Draft,
+                Party::Customer,
+                None,
+            ),
+            (
+                ProjectStatus::Published,
+                ProjectStatus::Closed,
+                Party::Customer,
+                None,
+            ),
+            (
+                ProjectStatus::Draft,
+                ProjectStatus::Published,
+                Party::Auditor,
+                Some(ErrorCode::Forbidden),
+            ),
+            (
+                ProjectStatus::Closed,
+                ProjectStatus::Published,
+                Party::Customer,
+                Some(ErrorCode::Conflict),
+            ),
+            (
+                ProjectStatus::Draft,
+                ProjectStatus::Draft,
+                Party::Customer,
+                Some(ErrorCode::Conflict),
+            ),
+        ] {
+            let result = from.check_transition(to, party);
+            assert_eq!(
+                result.err().map(|err| err.code()),
+                error,
+                "{:?} -> {:?}",
+                from,
+                to
+            );
+        }
+    }
+}
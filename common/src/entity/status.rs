@@ -0,0 +1,47 @@
+use std::fmt::Debug;
+
+use serde::Serialize;
+
+use crate::error::ServiceError;
+
+use super::audit_request::Party;
+
+/// Status of an entity that only moves along a fixed set of transitions.
+pub trait Lifecycle: Copy + PartialEq + Debug + Serialize + 'static {
+    /// Allowed transitions, as `(from, to, party making it)`.
+    const TRANSITIONS: &'static [(Self, Self, Party)];
+
+    /// Fails unless `party` may move the status from `self` to `to`.
+    fn check_transition(self, to: Self, party: Party) -> Result<(), ServiceError> {
+        let allowed = Self::TRANSITIONS
+            .iter()
+            .filter(|(from, next, _)| *from == self && *next == to)
+            .map(|(_, _, party)| *party)
+            .collect::<Vec<_>>();
+
+        if allowed.is_empty() {
+            return Err(ServiceError::Conflict(format!(
+                "Status can't change from `{}` to `{}`",
+                label(self),
+                label(to)
+            )));
+        }
+        if !allowed.contains(&party) {
+            return Err(ServiceError::Forbidden(format!(
+                "Only the {} can change the status from `{}` to `{}`",
+                label(allowed[0]),
+                label(self),
+                label(to)
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Name of `value` as it is serialized.
+fn label(value: impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(label)) => label,
+        other => format!("{:?}", other),
+    }
+}
@@ -1,51 +1,37 @@
-use axum::async_trait;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
-use crate::{context::MutationContext, repository::Method};
+use crate::{
+    auth::{
+        policy::{Access, Policy},
+        Auth,
+    },
+    repository::Method,
+};
 
 use super::Entity;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Values of `User::current_role`, each backed by a profile of the same name.
+pub const ROLES: &[&str] = &["customer", "auditor"];
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[entity(name = "user", public = "PublicUser")]
 pub struct User {
     #[serde(rename = "_id")]
+    #[entity(owner)]
     pub id: ObjectId,
     pub email: String,
     pub name: String,
     pub current_role: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PublicUser {
-    pub id: String,
-    pub email: String,
-    pub name: String,
-    pub current_role: String,
-}
-
-#[async_trait]
-impl Entity<User> for User {
-    type PublicEntity = PublicUser;
-
-    const NAME: &'static str = "user";
-
-    fn to_public(&self, context: &mut crate::context::MutationContext) -> Self::PublicEntity {
-        todo!()
-    }
-
-    async fn before_execution(
-        &self,
-        context: &mut MutationContext,
-        method: Method,
-    ) -> anyhow::Result<bool> {
-        todo!()
-    }
-
-    async fn after_execution(
-        &self,
-        context: &mut MutationContext,
-        method: Method,
-    ) -> anyhow::Result<bool> {
-        todo!()
+impl Policy for User {
+    fn access(auth: Option<&Auth>, method: Method) -> Access {
+        match (auth, method) {
+            (Some(Auth::Admin(_)), _) => Access::Allow,
+            (Some(Auth::User(_)), Method::Delete) => Access::Deny,
+            (Some(Auth::User(_)), _) => Access::Owner,
+            _ => Access::Deny,
+        }
     }
 }
@@ -1,28 +1,246 @@
+use std::fmt;
+
 use axum::{
     response::{IntoResponse, Response},
     Json,
 };
 use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthError;
 
 pub type ServiceResponse<T> = Result<Json<T>, ServiceError>;
 
-pub struct ServiceError(anyhow::Error);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    Conflict,
+    Upstream,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Body of every error response returned by a service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum ServiceError {
+    NotFound(String),
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Upstream(String),
+    Internal(anyhow::Error),
+}
+
+impl ServiceError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServiceError::NotFound(_) => ErrorCode::NotFound,
+            ServiceError::BadRequest(_) => ErrorCode::BadRequest,
+            ServiceError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ServiceError::Forbidden(_) => ErrorCode::Forbidden,
+            ServiceError::Conflict(_) => ErrorCode::Conflict,
+            ServiceError::Upstream(_) => ErrorCode::Upstream,
+            ServiceError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ServiceError {
+    /// Local equivalent of an error response from another service, decoded from its
+    /// [`ErrorBody`] when it has one.
+    ///
+    /// Internal errors of the peer surface as `Upstream`, since they aren't ours.
+    pub fn from_remote(peer: &str, status: StatusCode, body: Option<ErrorBody>) -> Self {
+        let code = match &body {
+            Some(body) => body.code,
+            None => match status {
+                StatusCode::NOT_FOUND => ErrorCode::NotFound,
+                StatusCode::BAD_REQUEST => ErrorCode::BadRequest,
+                StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+                StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+                StatusCode::CONFLICT => ErrorCode::Conflict,
+                _ => ErrorCode::Upstream,
+            },
+        };
+        let message = match body {
+            Some(body) if matches!(code, ErrorCode::Upstream | ErrorCode::Internal) => {
+                format!("{}: {}", peer, body.message)
+            }
+            Some(body) => body.message,
+            None => format!("{} responded with {}", peer, status),
+        };
+
+        match code {
+            ErrorCode::NotFound => ServiceError::NotFound(message),
+            ErrorCode::BadRequest => ServiceError::BadRequest(message),
+            ErrorCode::Unauthorized => ServiceError::Unauthorized(message),
+            ErrorCode::Forbidden => ServiceError::Forbidden(message),
+            ErrorCode::Conflict => ServiceError::Conflict(message),
+            ErrorCode::Upstream | ErrorCode::Internal => ServiceError::Upstream(message),
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::NotFound(message)
+            | ServiceError::BadRequest(message)
+            | ServiceError::Unauthorized(message)
+            | ServiceError::Forbidden(message)
+            | ServiceError::Conflict(message)
+            | ServiceError::Upstream(message) => f.write_str(message),
+            ServiceError::Internal(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// Recovers the typed error from `anyhow` based code, so repositories and
+/// hooks can return `ServiceError` through `anyhow::Result`.
+impl From<anyhow::Error> for ServiceError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<ServiceError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<AuthError>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<mongodb::bson::oid::Error>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<mongodb::error::Error>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        match err.downcast::<reqwest::Error>() {
+            Ok(err) => err.into(),
+            Err(err) => ServiceError::Internal(err),
+        }
+    }
+}
 
-impl<E> From<E> for ServiceError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+impl From<mongodb::bson::oid::Error> for ServiceError {
+    fn from(err: mongodb::bson::oid::Error) -> Self {
+        ServiceError::BadRequest(format!("Invalid id: {}", err))
+    }
+}
+
+impl From<mongodb::bson::ser::Error> for ServiceError {
+    fn from(err: mongodb::bson::ser::Error) -> Self {
+        ServiceError::Internal(err.into())
+    }
+}
+
+impl From<mongodb::bson::de::Error> for ServiceError {
+    fn from(err: mongodb::bson::de::Error) -> Self {
+        ServiceError::BadRequest(format!("Invalid document: {}", err))
+    }
+}
+
+/// Mongo's code for unique index violations.
+const DUPLICATE_KEY: i32 = 11000;
+
+impl From<mongodb::error::Error> for ServiceError {
+    fn from(err: mongodb::error::Error) -> Self {
+        use mongodb::error::{ErrorKind, WriteFailure};
+
+        let code = match err.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(err)) => Some(err.code),
+            ErrorKind::Command(err) => Some(err.code),
+            _ => None,
+        };
+        if code == Some(DUPLICATE_KEY) {
+            return ServiceError::Conflict(
+                "A document with the same unique value exists".to_string(),
+            );
+        }
+        ServiceError::Upstream(format!("Database error: {}", err))
+    }
+}
+
+impl From<reqwest::Error> for ServiceError {
+    fn from(err: reqwest::Error) -> Self {
+        ServiceError::Upstream(format!("Request error: {}", err))
     }
 }
 
 impl IntoResponse for ServiceError {
     fn into_response(self) -> Response {
-        (
+        let code = self.code();
+        let message = match &self {
+            ServiceError::Internal(err) => {
+                tracing::error!("internal error: {:?}", err);
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        (code.status(), Json(ErrorBody { code, message })).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_remote_errors() {
+        let body = |code| {
+            Some(ErrorBody {
+                code,
+                message: "message".to_string(),
+            })
+        };
+
+        let err = ServiceError::from_remote("project", StatusCode::NOT_FOUND, None);
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let err =
+            ServiceError::from_remote("project", StatusCode::FORBIDDEN, body(ErrorCode::Forbidden));
+        assert_eq!(err.code(), ErrorCode::Forbidden);
+        assert_eq!(err.to_string(), "message");
+
+        let err = ServiceError::from_remote(
+            "project",
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error message: {}", self.0),
-        )
-            .into_response()
+            body(ErrorCode::Internal),
+        );
+        assert_eq!(err.code(), ErrorCode::Upstream);
+
+        let err = ServiceError::from_remote("project", StatusCode::SERVICE_UNAVAILABLE, None);
+        assert_eq!(err.code(), ErrorCode::Upstream);
+        assert_eq!(
+            err.to_string(),
+            "project responded with 503 Service Unavailable"
+        );
     }
 }
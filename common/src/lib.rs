@@ -1,6 +1,18 @@
+// Lets `#[derive(Entity)]` refer to `::common` from inside this crate as well.
+extern crate self as common;
+
 pub mod auth;
 pub mod context;
 pub mod entity;
 pub mod error;
 pub mod repository;
+pub mod resilience;
 pub mod services;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+    pub use axum::async_trait;
+    pub use mongodb::bson;
+    pub use serde;
+}
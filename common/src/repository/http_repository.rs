@@ -4,105 +4,297 @@ use axum::{
     async_trait,
     body::Body,
     extract::Path,
-    routing::{delete, get, post},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
-use axum_macros::debug_handler;
-use mongodb::bson::{oid::ObjectId, Document};
-use serde::{de::DeserializeOwned, Serialize};
+use mongodb::bson::{from_document, oid::ObjectId, to_document, Document};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
-    context::{Context, ContextExtractor, ServiceState},
+    auth::policy::{check_access, check_owner, viewer, Access, Policy},
+    context::{Context, ContextExtractor, MutationContext, ServiceState},
     entity::Entity,
-    error::ServiceResponse,
+    error::{ErrorBody, ServiceError, ServiceResponse},
+    resilience::peer_name,
+    services::ServiceRegistry,
 };
 
-use super::{ReadRepositoryTrait, RepositoryTrait};
+use super::{Method, Page, Query, ReadRepositoryTrait, RepositoryTrait};
 
 pub trait Registrable {
     fn register<T>(self) -> Self
     where
-        T: Entity<T> + Serialize + DeserializeOwned + Sync + Send + 'static;
+        T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static;
 }
-async fn server_find<T: 'static>(
+
+/// Entity as the generated routes respond with it: whole to other services, which decode
+/// it with `HttpRepositoryClient`, and as its `PublicEntity` to everyone else.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Exposed<T: Entity<T>> {
+    Full(T),
+    Public(T::PublicEntity),
+}
+
+impl<T: Entity<T> + Serialize + Sync> Exposed<T> {
+    async fn new(entity: T, context: &mut MutationContext<'_>) -> anyhow::Result<Self> {
+        if context.context.1.is_service() {
+            return Ok(Exposed::Full(entity));
+        }
+        context.viewer = viewer(&entity, context.context).await?;
+        Ok(Exposed::Public(entity.to_public(context)))
+    }
+
+    async fn option(entity: Option<T>, context: &Context) -> anyhow::Result<Option<Self>> {
+        match entity {
+            Some(entity) => Ok(Some(
+                Self::new(entity, &mut MutationContext::new(context)).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn page(page: Page<T>, context: &Context) -> anyhow::Result<Page<Self>> {
+        let mut context = MutationContext::new(context);
+        let mut items = Vec::with_capacity(page.items.len());
+        for entity in page.items {
+            items.push(Self::new(entity, &mut context).await?);
+        }
+        Ok(Page {
+            items,
+            total: page.total,
+            next: page.next,
+        })
+    }
+}
+
+async fn server_find<T>(
     id: Path<String>,
     ContextExtractor(context): ContextExtractor,
-) -> ServiceResponse<Option<T>>
+) -> ServiceResponse<Option<Exposed<T>>>
 where
-    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
 {
     let id = ObjectId::from_str(&id)?;
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::Find)?;
 
-    let repository = context
-        .get_repository::<T>()
-        .ok_or(anyhow::anyhow!("Repository not found"))?;
+    let repository = context.repository::<T>()?;
 
     let result = repository.find(&id, &context).await?;
+    if let Some(entity) = &result {
+        check_owner(entity, auth, access)?;
+    }
 
-    Ok(Json(result))
+    Ok(Json(Exposed::option(result, &context).await?))
 }
 
-async fn server_find_by_doc<T: 'static>(
+async fn server_find_by_doc<T>(
     ContextExtractor(context): ContextExtractor,
     Json(document): Json<Document>,
-) -> ServiceResponse<Option<T>>
+) -> ServiceResponse<Option<Exposed<T>>>
 where
-    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
 {
-    let repository = context
-        .get_repository::<T>()
-        .ok_or(anyhow::anyhow!("Repository not found"))?;
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::FindByDoc)?;
+
+    let repository = context.repository::<T>()?;
 
     let result = repository.find_by_doc(document, &context).await?;
+    if let Some(entity) = &result {
+        check_owner(entity, auth, access)?;
+    }
 
-    Ok(Json(result))
+    Ok(Json(Exposed::option(result, &context).await?))
 }
 
-async fn server_insert<T: 'static>(
+async fn server_find_many<T>(
+    ContextExtractor(context): ContextExtractor,
+    Json(query): Json<Query>,
+) -> ServiceResponse<Page<Exposed<T>>>
+where
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
+{
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::FindMany)?;
+
+    let repository = context.repository::<T>()?;
+
+    let result = repository.find_many(query, &context).await?;
+    for entity in &result.items {
+        check_owner(entity, auth, access)?;
+    }
+
+    Ok(Json(Exposed::page(result, &context).await?))
+}
+
+async fn server_insert<T>(
     ContextExtractor(context): ContextExtractor,
     Json(entity): Json<T>,
-) -> ServiceResponse<bool>
+) -> ServiceResponse<()>
 where
-    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
 {
-    let repository = context
-        .get_repository::<T>()
-        .ok_or(anyhow::anyhow!("Repository not found"))?;
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::Insert)?;
+    check_owner(&entity, auth, access)?;
 
-    let result = repository.insert(&entity, &context).await?;
+    let repository = context.repository::<T>()?;
 
-    Ok(Json(result))
+    repository.insert(&entity, &context).await?;
+
+    Ok(Json(()))
 }
 
-async fn server_delete<T: 'static>(
+async fn server_delete<T>(
     id: Path<String>,
     ContextExtractor(context): ContextExtractor,
-) -> ServiceResponse<Option<T>>
+) -> ServiceResponse<Option<Exposed<T>>>
 where
-    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
 {
     let id = ObjectId::from_str(&id)?;
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::Delete)?;
 
-    let repository = context
-        .get_repository::<T>()
-        .ok_or(anyhow::anyhow!("Repository not found"))?;
+    let repository = context.repository::<T>()?;
+
+    if access == Access::Owner {
+        let Some(entity) = repository.find(&id, &context).await? else {
+            return Ok(Json(None));
+        };
+        check_owner(&entity, auth, access)?;
+    }
 
     let result = repository.delete(id, &context).await?;
 
-    Ok(Json(result))
+    Ok(Json(Exposed::option(result, &context).await?))
+}
+
+async fn server_update<T>(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+    Json(entity): Json<T>,
+) -> ServiceResponse<Option<Exposed<T>>>
+where
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
+{
+    let update = ConditionalUpdate {
+        condition: Document::new(),
+        entity,
+    };
+    server_update_if(id, ContextExtractor(context), Json(update)).await
+}
+
+/// Body of the conditional update route.
+#[derive(Serialize, Deserialize)]
+struct ConditionalUpdate<T> {
+    condition: Document,
+    entity: T,
+}
+
+async fn server_update_if<T>(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+    Json(ConditionalUpdate { condition, entity }): Json<ConditionalUpdate<T>>,
+) -> ServiceResponse<Option<Exposed<T>>>
+where
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
+{
+    let id = ObjectId::from_str(&id)?;
+    if let Ok(entity_id) = to_document(&entity)?.get_object_id("_id") {
+        if entity_id != id {
+            return Err(ServiceError::BadRequest(
+                "Entity id doesn't match the path".to_string(),
+            ));
+        }
+    }
+
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::Update)?;
+    check_owner(&entity, auth, access)?;
+
+    let repository = context.repository::<T>()?;
+
+    if access == Access::Owner {
+        let Some(current) = repository.find(&id, &context).await? else {
+            return Ok(Json(None));
+        };
+        check_owner(&current, auth, access)?;
+    }
+
+    let result = repository
+        .update_if(id, condition, &entity, &context)
+        .await?;
+
+    Ok(Json(Exposed::option(result, &context).await?))
+}
+
+async fn server_patch<T>(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+    Json(patch): Json<Document>,
+) -> ServiceResponse<Option<Exposed<T>>>
+where
+    T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
+{
+    let id = ObjectId::from_str(&id)?;
+    let auth = context.1.end_user();
+    let access = check_access::<T>(auth, Method::Update)?;
+
+    let repository = context.repository::<T>()?;
+
+    if access == Access::Owner {
+        let Some(current) = repository.find(&id, &context).await? else {
+            return Ok(Json(None));
+        };
+        check_owner(&current, auth, access)?;
+
+        // Nor may the patch hand the document to someone else. Invalid patches are left to
+        // the repository to reject.
+        let mut patched = to_document(&current)?;
+        patched.extend(patch.clone());
+        if let Ok(patched) = from_document::<T>(patched) {
+            check_owner(&patched, auth, access)?;
+        }
+    }
+
+    let result = repository.patch(id, patch, &context).await?;
+
+    Ok(Json(Exposed::option(result, &context).await?))
 }
 
 impl Registrable for Router<Arc<ServiceState>, Body> {
     fn register<T>(self) -> Self
     where
-        T: Entity<T> + Serialize + DeserializeOwned + Sync + Send + 'static,
+        T: Entity<T> + Policy + Serialize + DeserializeOwned + Sync + Send + 'static,
     {
         self.route(&format!("/api/{}/find/:id", T::NAME), get(server_find::<T>))
             .route(
                 &format!("/api/{}/find_by_doc", T::NAME),
                 post(server_find_by_doc::<T>),
             )
-            .route(&format!("/api/{}/insert", T::NAME), post(server_insert::<T>))
+            .route(
+                &format!("/api/{}/list", T::NAME),
+                post(server_find_many::<T>),
+            )
+            .route(
+                &format!("/api/{}/insert", T::NAME),
+                post(server_insert::<T>),
+            )
+            .route(
+                &format!("/api/{}/update/:id", T::NAME),
+                put(server_update::<T>),
+            )
+            .route(
+                &format!("/api/{}/update_if/:id", T::NAME),
+                put(server_update_if::<T>),
+            )
+            .route(
+                &format!("/api/{}/patch/:id", T::NAME),
+                patch(server_patch::<T>),
+            )
             .route(
                 &format!("/api/{}/delete/:id", T::NAME),
                 delete(server_delete::<T>),
@@ -110,11 +302,45 @@ impl Registrable for Router<Arc<ServiceState>, Body> {
     }
 }
 
+/// Repository backed by the routes `Registrable::register` generates on another service.
 pub struct HttpRepositoryClient<T> {
     origin: String,
     _t: PhantomData<T>,
 }
 
+impl<T: Entity<T>> HttpRepositoryClient<T> {
+    /// Client of the service at `origin`, a base url like `https://project:3003`.
+    pub fn new(origin: impl Into<String>) -> Self {
+        Self {
+            origin: origin.into().trim_end_matches('/').to_string(),
+            _t: PhantomData,
+        }
+    }
+
+    /// Client of `service`, resolved with `registry`.
+    pub fn from_registry(registry: &ServiceRegistry, service: &str) -> anyhow::Result<Self> {
+        Ok(Self::new(registry.resolve(service)?))
+    }
+
+    fn url(&self, route: &str) -> String {
+        format!("{}/api/{}/{}", self.origin, T::NAME, route)
+    }
+}
+
+/// Decodes a successful response, or re-raises the error the peer responded with.
+async fn decode<R: DeserializeOwned>(response: reqwest::Response) -> anyhow::Result<R> {
+    let peer = peer_name(response.url());
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.json::<ErrorBody>().await.ok();
+        return Err(ServiceError::from_remote(&peer, status, body).into());
+    }
+
+    response.json::<R>().await.map_err(|err| {
+        ServiceError::Upstream(format!("Invalid response from {}: {}", peer, err)).into()
+    })
+}
+
 #[async_trait]
 impl<T> ReadRepositoryTrait<T> for HttpRepositoryClient<T>
 where
@@ -123,31 +349,33 @@ where
 {
     async fn find(&self, id: &ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
         let response = context
-            .make_request::<()>() // TODO request with service jwt
-            .get(format!(
-                "{}://{}/api/{}/find/{}",
-                "http",
-                T::NAME,
-                "test",
-                id.to_hex()
-            ))
+            .make_request()
+            .get(self.url(&format!("find/{}", id.to_hex())))
             .send()
             .await?;
-        Ok(response.json::<T>().await.ok())
+        decode(response).await
     }
 
     async fn find_by_doc(&self, doc: Document, context: &Context) -> anyhow::Result<Option<T>> {
         let response = context
-            .0
-            .client
-            .post(format!(
-                "{}://{}/api/project/find_by_doc",
-                "http", self.origin
-            ))
+            .make_request()
+            .post(self.url("find_by_doc"))
+            .idempotent()
             .json(&doc)
             .send()
             .await?;
-        Ok(response.json::<T>().await.ok())
+        decode(response).await
+    }
+
+    async fn find_many(&self, query: Query, context: &Context) -> anyhow::Result<Page<T>> {
+        let response = context
+            .make_request()
+            .post(self.url("list"))
+            .idempotent()
+            .json(&query)
+            .send()
+            .await?;
+        decode(response).await
     }
 }
 
@@ -157,27 +385,165 @@ where
     T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
     Self: Sync,
 {
-    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<bool> {
+    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<()> {
         let response = context
             .make_request()
-            .post(format!("{}://{}/api/project/insert", "http", self.origin))
+            .post(self.url("insert"))
             .json(&entity)
             .send()
             .await?;
-        Ok(response.json::<bool>().await?)
+        decode(response).await
+    }
+
+    async fn update(
+        &self,
+        id: ObjectId,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        let response = context
+            .make_request()
+            .put(self.url(&format!("update/{}", id.to_hex())))
+            .json(entity)
+            .send()
+            .await?;
+        decode(response).await
+    }
+
+    async fn update_if(
+        &self,
+        id: ObjectId,
+        condition: Document,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        let response = context
+            .make_request()
+            .put(self.url(&format!("update_if/{}", id.to_hex())))
+            .json(&ConditionalUpdate { condition, entity })
+            .send()
+            .await?;
+        decode(response).await
+    }
+
+    async fn patch(
+        &self,
+        id: ObjectId,
+        patch: Document,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        let response = context
+            .make_request()
+            .patch(self.url(&format!("patch/{}", id.to_hex())))
+            .json(&patch)
+            .send()
+            .await?;
+        decode(response).await
     }
 
     async fn delete(&self, id: ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
         let response = context
-            .make_request::<()>()
-            .delete(format!(
-                "{}://{}/api/project/delete/{}",
-                "http",
-                self.origin,
-                id.to_hex()
-            ))
+            .make_request()
+            .delete(self.url(&format!("delete/{}", id.to_hex())))
             .send()
             .await?;
-        Ok(response.json::<T>().await.ok())
+        decode(response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        auth::Auth,
+        entity::{OptionallyPrivate, Private, Unique},
+    };
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+    #[entity(name = "secret", counterpart = "shares_secret")]
+    struct Secret {
+        #[serde(rename = "_id")]
+        #[entity(owner)]
+        id: ObjectId,
+        name: Unique<String>,
+        token: Private<String>,
+        contacts: HashMap<String, OptionallyPrivate<String>>,
+        shared_with: Vec<ObjectId>,
+    }
+
+    async fn shares_secret(secret: &Secret, user: ObjectId, _: &Context) -> anyhow::Result<bool> {
+        Ok(secret.shared_with.contains(&user))
+    }
+
+    fn secret(shared_with: ObjectId) -> Secret {
+        let contact = |value: &str, is_private| OptionallyPrivate {
+            is_private,
+            value: value.to_string(),
+        };
+        Secret {
+            id: ObjectId::new(),
+            name: Unique::new("api".to_string()),
+            token: Private::new("hunter2".to_string()),
+            contacts: HashMap::from([
+                ("email".to_string(), contact("api@example.com", false)),
+                ("phone".to_string(), contact("555-0100", true)),
+            ]),
+            shared_with: vec![shared_with],
+        }
+    }
+
+    async fn expose(secret: &Secret, user_auth: Option<Auth>) -> serde_json::Value {
+        let context = Context::test(ServiceState::new("test".to_string()), user_auth);
+        let page = Page {
+            items: vec![secret.clone()],
+            total: 1,
+            next: None,
+        };
+        let page = Exposed::page(page, &context).await.unwrap();
+        serde_json::to_value(page).unwrap()["items"][0].clone()
+    }
+
+    #[tokio::test]
+    async fn exposes_private_fields_to_services_only() {
+        let secret = secret(ObjectId::new());
+
+        let service = expose(&secret, Some(Auth::Service("project".to_string()))).await;
+        assert_eq!(service["token"], "hunter2");
+        assert_eq!(service["_id"], serde_json::json!(secret.id));
+
+        let user = expose(&secret, Some(Auth::User(ObjectId::new()))).await;
+        assert_eq!(user["_id"], secret.id.to_hex());
+        assert_eq!(user["name"], "api");
+        assert!(user.get("token").is_none());
+    }
+
+    #[tokio::test]
+    async fn shows_optionally_private_values_by_viewer() {
+        let counterpart = ObjectId::new();
+        let secret = secret(counterpart);
+
+        for (auth, sees_private) in [
+            (None, false),
+            (Some(Auth::User(ObjectId::new())), false),
+            (Some(Auth::User(counterpart)), true),
+            (Some(Auth::User(secret.id)), true),
+            (Some(Auth::Admin(ObjectId::new())), true),
+        ] {
+            let contacts = expose(&secret, auth.clone()).await["contacts"].clone();
+            assert_eq!(contacts["email"], "api@example.com", "{:?}", auth);
+            assert_eq!(
+                contacts["phone"],
+                match sees_private {
+                    true => serde_json::json!("555-0100"),
+                    false => serde_json::Value::Null,
+                },
+                "{:?}",
+                auth
+            );
+        }
     }
 }
@@ -0,0 +1,702 @@
+use std::{cmp::Ordering, marker::PhantomData, sync::RwLock};
+
+use axum::async_trait;
+use mongodb::bson::{doc, from_document, oid::ObjectId, to_document, Bson, Document};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    auth::policy::{check_scope, scope_filter},
+    context::{Context, MutationContext},
+    entity::Entity,
+    error::ServiceError,
+};
+
+use super::{Method, Page, Query, ReadRepositoryTrait, RepositoryTrait};
+
+/// Repository keeping its documents in memory, for tests and running a service without
+/// a database.
+///
+/// Behaves like `MongoRepository`, running the same hooks and row level security. Filters
+/// support field equality (including dotted paths and array membership), `$eq`, `$ne`,
+/// `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`, `$exists`, `$not`, `$and`, `$or` and `$nor`.
+pub struct InMemoryRepository<T> {
+    documents: RwLock<Vec<Document>>,
+    _t: PhantomData<T>,
+}
+
+impl<T> Default for InMemoryRepository<T> {
+    fn default() -> Self {
+        Self {
+            documents: RwLock::new(Vec::new()),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize> InMemoryRepository<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Repository holding `entities`, inserted without running hooks.
+    pub fn with(entities: impl IntoIterator<Item = T>) -> anyhow::Result<Self> {
+        let repository = Self::new();
+        {
+            let mut documents = repository.documents.write().unwrap();
+            for entity in entities {
+                documents.push(with_id(to_document(&entity)?));
+            }
+        }
+        Ok(repository)
+    }
+}
+
+impl<T: DeserializeOwned> InMemoryRepository<T> {
+    fn find_one(&self, filter: &Document) -> anyhow::Result<Option<T>> {
+        let documents = self.documents.read().unwrap();
+        match documents.iter().find(|document| matches(document, filter)) {
+            Some(document) => Ok(Some(from_document(document.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies `change` to the first document matching `filter`, returning it afterwards.
+    fn modify(
+        &self,
+        filter: &Document,
+        change: impl FnOnce(&mut Document),
+    ) -> anyhow::Result<Option<T>> {
+        let mut documents = self.documents.write().unwrap();
+        let Some(document) = documents
+            .iter_mut()
+            .find(|document| matches(document, filter))
+        else {
+            return Ok(None);
+        };
+        change(document);
+        Ok(Some(from_document(document.clone())?))
+    }
+}
+
+fn with_id(mut document: Document) -> Document {
+    if !document.contains_key("_id") {
+        document.insert("_id", ObjectId::new());
+    }
+    document
+}
+
+#[async_trait]
+impl<T> ReadRepositoryTrait<T> for InMemoryRepository<T>
+where
+    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+{
+    async fn find(&self, id: &ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
+        self.find_by_doc(doc! {"_id": id}, context).await
+    }
+
+    async fn find_by_doc(&self, doc: Document, context: &Context) -> anyhow::Result<Option<T>> {
+        let filter = scope_filter::<T>(doc, context.1.end_user());
+        let Some(entity) = self.find_one(&filter)? else {
+            return Ok(None);
+        };
+
+        let mut context = MutationContext::new(context);
+        if !entity
+            .after_execution(&mut context, Method::Find)
+            .await?
+            .visible()?
+        {
+            return Ok(None);
+        }
+        Ok(Some(entity))
+    }
+
+    async fn find_many(&self, query: Query, context: &Context) -> anyhow::Result<Page<T>> {
+        if query.after.is_some() && query.sort.is_some() {
+            return Err(ServiceError::BadRequest(
+                "Cursor pagination can't be combined with sort".to_string(),
+            )
+            .into());
+        }
+
+        let filter = scope_filter::<T>(query.filter.clone(), context.1.end_user());
+        let mut matching: Vec<Document> = self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|document| matches(document, &filter))
+            .cloned()
+            .collect();
+        let total = matching.len() as u64;
+
+        let sort = query.sort.clone().unwrap_or_else(|| doc! { "_id": 1 });
+        matching.sort_by(|a, b| compare_by(a, b, &sort));
+        if let Some(after) = query.after {
+            matching.retain(|document| {
+                compare(document.get("_id"), Some(&Bson::ObjectId(after))) == Ordering::Greater
+            });
+        }
+
+        let page_size = query.page_size();
+        let page: Vec<Document> = matching
+            .into_iter()
+            .skip(query.skip.unwrap_or(0) as usize)
+            .take(page_size as usize)
+            .collect();
+        let fetched = page.len() as i64;
+        let last_id = page
+            .last()
+            .and_then(|document| document.get_object_id("_id").ok());
+
+        let mut context = MutationContext::new(context);
+        let mut items = Vec::new();
+        for document in page {
+            let entity: T = from_document(document)?;
+            if entity
+                .after_execution(&mut context, Method::FindMany)
+                .await?
+                .visible()?
+            {
+                items.push(entity);
+            }
+        }
+
+        let next = if query.pages_by_id() && fetched == page_size {
+            last_id
+        } else {
+            None
+        };
+        Ok(Page { items, total, next })
+    }
+}
+
+#[async_trait]
+impl<T> RepositoryTrait<T> for InMemoryRepository<T>
+where
+    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
+{
+    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<()> {
+        check_scope(entity, context.1.end_user())?;
+
+        let mut context = MutationContext::new(context);
+        entity
+            .before_execution(&mut context, Method::Insert)
+            .await?
+            .visible()?;
+
+        let document = with_id(to_document(entity)?);
+        {
+            let mut documents = self.documents.write().unwrap();
+            let id = document.get("_id");
+            if documents.iter().any(|stored| stored.get("_id") == id) {
+                return Err(ServiceError::Conflict("Duplicate id".to_string()).into());
+            }
+            documents.push(document);
+        }
+
+        entity
+            .after_execution(&mut context, Method::Insert)
+            .await?
+            .visible()?;
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        id: ObjectId,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        self.update_if(id, Document::new(), entity, context).await
+    }
+
+    async fn update_if(
+        &self,
+        id: ObjectId,
+        mut condition: Document,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        check_scope(entity, context.1.end_user())?;
+
+        condition.insert("_id", id);
+        let filter = scope_filter::<T>(condition, context.1.end_user());
+        let mut context = MutationContext::new(context);
+        let visible = entity
+            .before_execution(&mut context, Method::Update)
+            .await?
+            .visible()?;
+
+        let mut replacement = to_document(entity)?;
+        replacement.insert("_id", id);
+        let updated = self.modify(&filter, |document| *document = replacement)?;
+
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        let visible = updated
+            .after_execution(&mut context, Method::Update)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(updated))
+    }
+
+    async fn patch(
+        &self,
+        id: ObjectId,
+        patch: Document,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        if let Some(key) = patch
+            .keys()
+            .find(|key| *key == "_id" || key.starts_with('$') || key.contains('.'))
+        {
+            return Err(
+                ServiceError::BadRequest(format!("Field `{}` can't be patched", key)).into(),
+            );
+        }
+
+        let filter = scope_filter::<T>(doc! {"_id": id}, context.1.end_user());
+        let Some(current) = self.find_one(&filter)? else {
+            return Ok(None);
+        };
+
+        let mut patched = to_document(&current)?;
+        patched.extend(patch.clone());
+        let patched: T = from_document(patched)
+            .map_err(|err| ServiceError::BadRequest(format!("Invalid patch: {}", err)))?;
+        check_scope(&patched, context.1.end_user())?;
+
+        let mut context = MutationContext::new(context);
+        let visible = patched
+            .before_execution(&mut context, Method::Update)
+            .await?
+            .visible()?;
+
+        let updated = self.modify(&filter, |document| document.extend(patch))?;
+
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        let visible = updated
+            .after_execution(&mut context, Method::Update)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(updated))
+    }
+
+    async fn delete(&self, id: ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
+        let filter = scope_filter::<T>(doc! {"_id": id}, context.1.end_user());
+        let Some(current) = self.find_one(&filter)? else {
+            return Ok(None);
+        };
+
+        let mut context = MutationContext::new(context);
+        let visible = current
+            .before_execution(&mut context, Method::Delete)
+            .await?
+            .visible()?;
+
+        let removed = {
+            let mut documents = self.documents.write().unwrap();
+            documents
+                .iter()
+                .position(|document| matches(document, &filter))
+                .map(|index| documents.remove(index))
+        };
+        let Some(removed) = removed else {
+            return Ok(None);
+        };
+
+        let deleted: T = from_document(removed)?;
+        let visible = deleted
+            .after_execution(&mut context, Method::Delete)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(deleted))
+    }
+}
+
+/// Whether `document` matches the query `filter`.
+fn matches(document: &Document, filter: &Document) -> bool {
+    filter.iter().all(|(key, condition)| match key.as_str() {
+        "$and" => clauses(condition).all(|clause| matches(document, clause)),
+        "$or" => clauses(condition).any(|clause| matches(document, clause)),
+        "$nor" => !clauses(condition).any(|clause| matches(document, clause)),
+        _ => matches_field(lookup(document, key), condition),
+    })
+}
+
+fn clauses(condition: &Bson) -> impl Iterator<Item = &Document> {
+    condition
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Bson::as_document)
+}
+
+/// Value at the dotted `path` of `document`.
+fn lookup<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut current = document;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        let value = current.get(segment)?;
+        if segments.peek().is_none() {
+            return Some(value);
+        }
+        current = value.as_document()?;
+    }
+    None
+}
+
+fn matches_field(value: Option<&Bson>, condition: &Bson) -> bool {
+    match condition {
+        Bson::Document(operators) if operators.keys().any(|key| key.starts_with('$')) => operators
+            .iter()
+            .all(|(operator, operand)| matches_operator(value, operator, operand)),
+        _ => equals(value, condition),
+    }
+}
+
+fn matches_operator(value: Option<&Bson>, operator: &str, operand: &Bson) -> bool {
+    let ordered = |accept: fn(Ordering) -> bool| {
+        candidates(value)
+            .any(|value| comparable(value, operand) && accept(compare(Some(value), Some(operand))))
+    };
+
+    match operator {
+        "$eq" => equals(value, operand),
+        "$ne" => !equals(value, operand),
+        "$gt" => ordered(|ordering| ordering == Ordering::Greater),
+        "$gte" => ordered(|ordering| ordering != Ordering::Less),
+        "$lt" => ordered(|ordering| ordering == Ordering::Less),
+        "$lte" => ordered(|ordering| ordering != Ordering::Greater),
+        "$in" => operand
+            .as_array()
+            .is_some_and(|options| options.iter().any(|option| equals(value, option))),
+        "$nin" => operand
+            .as_array()
+            .is_some_and(|options| !options.iter().any(|option| equals(value, option))),
+        "$exists" => value.is_some() == operand.as_bool().unwrap_or(true),
+        "$not" => !matches_field(value, operand),
+        "$size" => match (value, number(operand)) {
+            (Some(Bson::Array(elements)), Some(size)) => elements.len() as f64 == size,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The value itself and, for arrays, their elements, as Mongo matches both.
+fn candidates(value: Option<&Bson>) -> impl Iterator<Item = &Bson> {
+    let elements = match value {
+        Some(Bson::Array(elements)) => elements.as_slice(),
+        _ => &[],
+    };
+    value.into_iter().chain(elements)
+}
+
+fn equals(value: Option<&Bson>, expected: &Bson) -> bool {
+    match value {
+        None => *expected == Bson::Null,
+        Some(_) => candidates(value).any(|value| match (number(value), number(expected)) {
+            (Some(a), Some(b)) => a == b,
+            _ => value == expected,
+        }),
+    }
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Double(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Whether `a` and `b` are of the same kind, numbers of any width being one kind.
+fn comparable(a: &Bson, b: &Bson) -> bool {
+    (number(a).is_some() && number(b).is_some())
+        || std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+fn compare(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    let (a, b) = match (a, b) {
+        (None, None) => return Ordering::Equal,
+        (None, Some(_)) => return Ordering::Less,
+        (Some(_), None) => return Ordering::Greater,
+        (Some(a), Some(b)) => (a, b),
+    };
+    if let (Some(a), Some(b)) = (number(a), number(b)) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => a.cmp(b),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        // Other values only compare for equality; unequal ones keep their order.
+        _ => Ordering::Equal,
+    }
+}
+
+fn compare_by(a: &Document, b: &Document, sort: &Document) -> Ordering {
+    for (key, direction) in sort {
+        let ordering = compare(lookup(a, key), lookup(b, key));
+        let ordering = if number(direction).is_some_and(|direction| direction < 0.0) {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{auth::Auth, context::ServiceState, entity::HookResult};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        #[serde(rename = "_id")]
+        id: ObjectId,
+        owner: ObjectId,
+        name: String,
+        score: i32,
+        tags: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Entity<Item> for Item {
+        type PublicEntity = ();
+
+        const NAME: &'static str = "item";
+
+        const OWNER_FIELDS: &'static [&'static str] = &["owner"];
+
+        fn to_public(&self, _: &mut MutationContext) -> Self::PublicEntity {}
+
+        async fn before_execution(
+            &self,
+            _: &mut MutationContext,
+            _: Method,
+        ) -> anyhow::Result<HookResult> {
+            Ok(match self.name.is_empty() {
+                true => HookResult::invalid("`name` can't be empty"),
+                false => HookResult::Continue,
+            })
+        }
+
+        async fn after_execution(
+            &self,
+            _: &mut MutationContext,
+            _: Method,
+        ) -> anyhow::Result<HookResult> {
+            Ok(match self.name.as_str() {
+                "hidden" => HookResult::Hide,
+                _ => HookResult::Continue,
+            })
+        }
+    }
+
+    fn item(owner: ObjectId, name: &str, score: i32, tags: &[&str]) -> Item {
+        Item {
+            id: ObjectId::new(),
+            owner,
+            name: name.to_string(),
+            score,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn context(user_auth: Option<Auth>) -> Context {
+        Context::test(ServiceState::new("test".to_string()), user_auth)
+    }
+
+    #[test]
+    fn matches_query_operators() {
+        let document = doc! {
+            "name": "audit",
+            "score": 7,
+            "tags": ["rust", "web"],
+            "meta": { "level": 2 },
+        };
+
+        assert!(matches(&document, &doc! { "name": "audit" }));
+        assert!(matches(&document, &doc! { "tags": "rust" }));
+        assert!(matches(&document, &doc! { "meta.level": 2_i64 }));
+        assert!(matches(
+            &document,
+            &doc! { "score": { "$gte": 7, "$lt": 8.5 } }
+        ));
+        assert!(matches(
+            &document,
+            &doc! { "name": { "$in": ["audit", "project"] } }
+        ));
+        assert!(matches(
+            &document,
+            &doc! { "missing": { "$exists": false } }
+        ));
+        assert!(matches(
+            &document,
+            &doc! { "$or": [{ "name": "x" }, { "score": 7 }] }
+        ));
+        assert!(matches(
+            &document,
+            &doc! { "score": { "$not": { "$gt": 10 } } }
+        ));
+        assert!(matches(&document, &doc! { "tags": { "$size": 2 } }));
+
+        assert!(!matches(&document, &doc! { "name": "project" }));
+        assert!(!matches(&document, &doc! { "meta": { "level": 3 } }));
+        assert!(!matches(&document, &doc! { "score": { "$gt": "7" } }));
+        assert!(!matches(&document, &doc! { "tags": { "$nin": ["web"] } }));
+        assert!(!matches(&document, &doc! { "$nor": [{ "meta.level": 2 }] }));
+        assert!(!matches(&document, &doc! { "name": { "$size": 5 } }));
+    }
+
+    #[tokio::test]
+    async fn runs_hooks() {
+        let context = context(None);
+        let repository = InMemoryRepository::<Item>::new();
+        let owner = ObjectId::new();
+
+        let visible = item(owner, "visible", 1, &[]);
+        let hidden = item(owner, "hidden", 2, &[]);
+        repository.insert(&visible, &context).await.unwrap();
+        repository.insert(&hidden, &context).await.unwrap();
+        let err = repository
+            .insert(&item(owner, "", 3, &[]), &context)
+            .await
+            .unwrap_err();
+        assert_eq!(ServiceError::from(err).to_string(), "`name` can't be empty");
+
+        assert_eq!(
+            repository.find(&visible.id, &context).await.unwrap(),
+            Some(visible.clone())
+        );
+        assert_eq!(repository.find(&hidden.id, &context).await.unwrap(), None);
+
+        let page = repository
+            .find_many(Query::default(), &context)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items, vec![visible]);
+    }
+
+    #[tokio::test]
+    async fn scopes_to_owner() {
+        let owner = ObjectId::new();
+        let repository = InMemoryRepository::with([
+            item(owner, "mine", 1, &[]),
+            item(ObjectId::new(), "theirs", 2, &[]),
+        ])
+        .unwrap();
+
+        let context = context(Some(Auth::User(owner)));
+        let page = repository
+            .find_many(Query::default(), &context)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].name, "mine");
+
+        let theirs = item(ObjectId::new(), "theirs", 3, &[]);
+        assert!(repository.insert(&theirs, &context).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pages_and_sorts() {
+        let owner = ObjectId::new();
+        let repository = InMemoryRepository::with(
+            (0..5).map(|score| item(owner, &format!("item-{}", score), score, &[])),
+        )
+        .unwrap();
+        let context = context(None);
+
+        let mut query = Query {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = repository.find_many(query.clone(), &context).await.unwrap();
+        assert_eq!(first.total, 5);
+        assert_eq!(first.items.len(), 2);
+
+        query.after = first.next;
+        let second = repository.find_many(query, &context).await.unwrap();
+        assert_eq!(second.items[0].score, 2);
+
+        let query = Query {
+            sort: Some(doc! { "score": -1 }),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let top = repository.find_many(query, &context).await.unwrap();
+        assert_eq!(top.items[0].score, 4);
+        assert_eq!(top.next, None);
+
+        let query = Query {
+            skip: Some(1),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let skipped = repository.find_many(query, &context).await.unwrap();
+        assert_eq!(skipped.items[0].score, 1);
+        assert_eq!(skipped.next, None);
+    }
+
+    #[tokio::test]
+    async fn updates_patches_and_deletes() {
+        let owner = ObjectId::new();
+        let stored = item(owner, "before", 1, &[]);
+        let repository = InMemoryRepository::with([stored.clone()]).unwrap();
+        let context = context(None);
+
+        let mut replacement = stored.clone();
+        replacement.score = 5;
+        let updated = repository
+            .update(stored.id, &replacement, &context)
+            .await
+            .unwrap();
+        assert_eq!(updated, Some(replacement.clone()));
+
+        replacement.score = 6;
+        let stale = repository
+            .update_if(stored.id, doc! { "score": 1 }, &replacement, &context)
+            .await
+            .unwrap();
+        assert_eq!(stale, None);
+        let current = repository
+            .update_if(stored.id, doc! { "score": 5 }, &replacement, &context)
+            .await
+            .unwrap();
+        assert_eq!(current, Some(replacement));
+
+        let patched = repository
+            .patch(stored.id, doc! { "name": "after" }, &context)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((patched.name.as_str(), patched.score), ("after", 6));
+        assert!(repository
+            .patch(stored.id, doc! { "score": "high" }, &context)
+            .await
+            .is_err());
+
+        let deleted = repository.delete(stored.id, &context).await.unwrap();
+        assert_eq!(deleted.map(|item| item.name), Some("after".to_string()));
+        assert_eq!(repository.find(&stored.id, &context).await.unwrap(), None);
+    }
+}
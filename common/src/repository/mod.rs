@@ -2,11 +2,12 @@ use std::sync::Arc;
 
 use axum::async_trait;
 use mongodb::bson::{oid::ObjectId, Document};
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{context::Context, entity::Entity};
 
 pub mod http_repository;
+pub mod memory;
 pub mod mongo;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,18 +15,94 @@ pub enum Method {
     Insert,
     Find,
     FindByDoc,
+    FindMany,
+    Update,
     Delete,
 }
 
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Query of `ReadRepositoryTrait::find_many`.
+///
+/// Pages are selected either with `skip` or, for stable pagination, with `after`: the
+/// `next` cursor of the previous page. Cursors page by `_id`, so they can't be combined
+/// with a custom `sort`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Query {
+    #[serde(default)]
+    pub filter: Document,
+    pub sort: Option<Document>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+    pub after: Option<ObjectId>,
+}
+
+impl Query {
+    pub fn new(filter: Document) -> Self {
+        Self {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    pub fn page_size(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Whether results come in plain `_id` order, the only one `next` cursors can resume.
+    fn pages_by_id(&self) -> bool {
+        self.sort.is_none() && self.skip.unwrap_or(0) == 0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of documents matching the filter, across all pages.
+    pub total: u64,
+    /// Cursor of the next page, if there may be one. Only set for queries without `sort`
+    /// and `skip`.
+    pub next: Option<ObjectId>,
+}
+
 #[async_trait]
 pub trait ReadRepositoryTrait<T> {
     async fn find(&self, id: &ObjectId, context: &Context) -> anyhow::Result<Option<T>>;
     async fn find_by_doc(&self, doc: Document, context: &Context) -> anyhow::Result<Option<T>>;
+    async fn find_many(&self, query: Query, context: &Context) -> anyhow::Result<Page<T>>;
 }
 
 #[async_trait]
 pub trait RepositoryTrait<T>: ReadRepositoryTrait<T> {
-    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<bool>;
+    /// Stores `entity`, failing with the error of the hook that aborted the insert, if any.
+    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<()>;
+    /// Replaces the document with id `id`, returning the stored entity.
+    async fn update(
+        &self,
+        id: ObjectId,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>>;
+    /// Like `update`, but only replaces the document while it also matches `condition`, so
+    /// read-modify-write cycles notice concurrent changes by getting `None`.
+    async fn update_if(
+        &self,
+        id: ObjectId,
+        condition: Document,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>>;
+    /// Sets the top level fields of `patch` on the document with id `id`, returning the
+    /// stored entity.
+    async fn patch(
+        &self,
+        id: ObjectId,
+        patch: Document,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>>;
     async fn delete(&self, entity: ObjectId, context: &Context) -> anyhow::Result<Option<T>>;
 }
 
@@ -46,6 +123,10 @@ impl<T> ReadRepositoryTrait<T> for Repository<T> {
     async fn find_by_doc(&self, doc: Document, context: &Context) -> anyhow::Result<Option<T>> {
         self.0.find_by_doc(doc, context).await
     }
+
+    async fn find_many(&self, query: Query, context: &Context) -> anyhow::Result<Page<T>> {
+        self.0.find_many(query, context).await
+    }
 }
 
 #[async_trait]
@@ -54,11 +135,39 @@ where
     T: Entity<T> + Serialize + DeserializeOwned + Sync + Send,
     Self: Sync,
 {
-    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<bool> {
+    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<()> {
         let future = self.0.insert(entity, context);
         future.await
     }
 
+    async fn update(
+        &self,
+        id: ObjectId,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        self.0.update(id, entity, context).await
+    }
+
+    async fn update_if(
+        &self,
+        id: ObjectId,
+        condition: Document,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        self.0.update_if(id, condition, entity, context).await
+    }
+
+    async fn patch(
+        &self,
+        id: ObjectId,
+        patch: Document,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        self.0.patch(id, patch, context).await
+    }
+
     async fn delete(&self, entity: ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
         self.0.delete(entity, context).await
     }
@@ -1,27 +1,56 @@
 use axum::async_trait;
 use mongodb::{
-    bson::{doc, oid::ObjectId, Document},
-    Collection,
+    bson::{doc, from_document, oid::ObjectId, to_document, Document},
+    options::{
+        FindOneAndReplaceOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions,
+        ReturnDocument,
+    },
+    Collection, IndexModel,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
+    auth::policy::{check_scope, scope_filter},
     context::{Context, MutationContext},
     entity::Entity,
+    error::ServiceError,
 };
 
-use super::{Method, ReadRepositoryTrait, RepositoryTrait};
+use super::{Method, Page, Query, ReadRepositoryTrait, RepositoryTrait};
 
 pub struct MongoRepository<T>(Collection<T>);
 
-impl<T> MongoRepository<T> {
+impl<T: Entity<T>> MongoRepository<T> {
     pub async fn new(mongo_uri: &str, database: &str, collection: &str) -> Self {
         let collection = mongodb::Client::with_uri_str(mongo_uri)
             .await
             .unwrap()
             .database(database)
             .collection(collection);
-        Self(collection)
+        let repository = Self(collection);
+        if let Err(err) = repository.create_unique_indexes().await {
+            tracing::error!("failed to create unique indexes of {}: {:?}", T::NAME, err);
+        }
+        repository
+    }
+
+    /// Backs the `Unique` fields of `T` with unique indexes, so concurrent inserts can't
+    /// both pass the check in `Unique::before_execution`.
+    async fn create_unique_indexes(&self) -> mongodb::error::Result<()> {
+        let mut fields = Vec::new();
+        T::unique_fields(None, &mut fields);
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let indexes = fields.into_iter().map(|field| {
+            IndexModel::builder()
+                .keys(doc! { field: 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()
+        });
+        self.0.create_indexes(indexes, None).await?;
+        Ok(())
     }
 }
 
@@ -32,12 +61,13 @@ where
     Self: Sync,
 {
     async fn find(&self, id: &ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
-        let entity: Option<T> = self.0.find_one(doc! {"_id": id}, None).await?;
+        let filter = scope_filter::<T>(doc! {"_id": id}, context.1.end_user());
+        let entity: Option<T> = self.0.find_one(filter, None).await?;
 
         if let Some(entity) = entity {
             let mut context = MutationContext::new(context);
             let future = entity.after_execution(&mut context, Method::Find);
-            if !future.await? {
+            if !future.await?.visible()? {
                 return Ok(None);
             }
             return Ok(Some(entity));
@@ -46,16 +76,74 @@ where
     }
 
     async fn find_by_doc(&self, doc: Document, context: &Context) -> anyhow::Result<Option<T>> {
-        let entity: Option<T> = self.0.find_one(doc, None).await?;
+        let filter = scope_filter::<T>(doc, context.1.end_user());
+        let entity: Option<T> = self.0.find_one(filter, None).await?;
         if let Some(entity) = entity {
             let mut context = MutationContext::new(context);
-            if !entity.after_execution(&mut context, Method::Find).await? {
+            if !entity
+                .after_execution(&mut context, Method::Find)
+                .await?
+                .visible()?
+            {
                 return Ok(None);
             }
             return Ok(Some(entity));
         }
         Ok(None)
     }
+
+    async fn find_many(&self, query: Query, context: &Context) -> anyhow::Result<Page<T>> {
+        if query.after.is_some() && query.sort.is_some() {
+            return Err(ServiceError::BadRequest(
+                "Cursor pagination can't be combined with sort".to_string(),
+            )
+            .into());
+        }
+
+        let filter = scope_filter::<T>(query.filter.clone(), context.1.end_user());
+        let total = self.0.count_documents(filter.clone(), None).await?;
+
+        let page_size = query.page_size();
+        let pages_by_id = query.pages_by_id();
+        let (filter, sort) = match query.after {
+            Some(after) => (
+                doc! { "$and": [filter, { "_id": { "$gt": after } }] },
+                doc! { "_id": 1 },
+            ),
+            None => (filter, query.sort.unwrap_or_else(|| doc! { "_id": 1 })),
+        };
+        let options = FindOptions::builder()
+            .sort(sort)
+            .skip(query.skip)
+            .limit(page_size)
+            .build();
+
+        let mut cursor = self.0.find(filter, options).await?;
+        let mut context = MutationContext::new(context);
+        let mut items = Vec::new();
+        let mut last_id = None;
+        let mut fetched = 0;
+
+        while cursor.advance().await? {
+            let entity: T = cursor.deserialize_current()?;
+            fetched += 1;
+            last_id = to_document(&entity)?.get_object_id("_id").ok();
+            if entity
+                .after_execution(&mut context, Method::FindMany)
+                .await?
+                .visible()?
+            {
+                items.push(entity);
+            }
+        }
+
+        let next = if pages_by_id && fetched == page_size {
+            last_id
+        } else {
+            None
+        };
+        Ok(Page { items, total, next })
+    }
 }
 
 #[async_trait]
@@ -64,26 +152,136 @@ where
     T: Entity<T> + Serialize + DeserializeOwned + Sync + Send + Unpin,
     Self: Sync,
 {
-    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<bool> {
+    async fn insert(&self, entity: &T, context: &Context) -> anyhow::Result<()> {
+        check_scope(entity, context.1.end_user())?;
+
         let mut context = MutationContext::new(context);
-        let abort = entity
+        entity
             .before_execution(&mut context, Method::Insert)
-            .await?;
-        if abort {
-            return Ok(true);
-        }
+            .await?
+            .visible()?;
         self.0.insert_one(entity, None).await?;
-        entity.after_execution(&mut context, Method::Insert).await?;
-        Ok(false)
+        entity
+            .after_execution(&mut context, Method::Insert)
+            .await?
+            .visible()?;
+        Ok(())
     }
 
-    async fn delete(&self, id: ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
-        let entity: Option<T> = self.0.find_one_and_delete(doc! {"_id": id}, None).await?;
-        if let Some(entity) = entity {
-            let mut context = MutationContext::new(context);
-            entity.after_execution(&mut context, Method::Delete).await?;
-            return Ok(Some(entity));
+    async fn update(
+        &self,
+        id: ObjectId,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        self.update_if(id, Document::new(), entity, context).await
+    }
+
+    async fn update_if(
+        &self,
+        id: ObjectId,
+        mut condition: Document,
+        entity: &T,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        check_scope(entity, context.1.end_user())?;
+
+        condition.insert("_id", id);
+        let filter = scope_filter::<T>(condition, context.1.end_user());
+        let mut context = MutationContext::new(context);
+        let visible = entity
+            .before_execution(&mut context, Method::Update)
+            .await?
+            .visible()?;
+
+        let options = FindOneAndReplaceOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let updated = self.0.find_one_and_replace(filter, entity, options).await?;
+
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        let visible = updated
+            .after_execution(&mut context, Method::Update)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(updated))
+    }
+
+    async fn patch(
+        &self,
+        id: ObjectId,
+        patch: Document,
+        context: &Context,
+    ) -> anyhow::Result<Option<T>> {
+        if let Some(key) = patch
+            .keys()
+            .find(|key| *key == "_id" || key.starts_with('$') || key.contains('.'))
+        {
+            return Err(
+                ServiceError::BadRequest(format!("Field `{}` can't be patched", key)).into(),
+            );
         }
-        Ok(None)
+
+        let filter = scope_filter::<T>(doc! {"_id": id}, context.1.end_user());
+        let Some(current) = self.0.find_one(filter.clone(), None).await? else {
+            return Ok(None);
+        };
+
+        // Hooks and ownership checks see the entity as it will be after the patch.
+        let mut patched = to_document(&current)?;
+        patched.extend(patch.clone());
+        let patched: T = from_document(patched)
+            .map_err(|err| ServiceError::BadRequest(format!("Invalid patch: {}", err)))?;
+        check_scope(&patched, context.1.end_user())?;
+
+        let mut context = MutationContext::new(context);
+        let visible = patched
+            .before_execution(&mut context, Method::Update)
+            .await?
+            .visible()?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let updated = self
+            .0
+            .find_one_and_update(filter, doc! { "$set": patch }, options)
+            .await?;
+
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        let visible = updated
+            .after_execution(&mut context, Method::Update)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(updated))
+    }
+
+    async fn delete(&self, id: ObjectId, context: &Context) -> anyhow::Result<Option<T>> {
+        let filter = scope_filter::<T>(doc! {"_id": id}, context.1.end_user());
+        let Some(current) = self.0.find_one(filter.clone(), None).await? else {
+            return Ok(None);
+        };
+
+        let mut context = MutationContext::new(context);
+        let visible = current
+            .before_execution(&mut context, Method::Delete)
+            .await?
+            .visible()?;
+
+        let Some(deleted) = self.0.find_one_and_delete(filter, None).await? else {
+            return Ok(None);
+        };
+        let visible = deleted
+            .after_execution(&mut context, Method::Delete)
+            .await?
+            .visible()?
+            && visible;
+        Ok(visible.then_some(deleted))
     }
 }
@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::header;
+use axum::response::IntoResponse;
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+use crate::services::REGISTRY;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RETRIES: u32 = 2;
+const BACKOFF_BASE: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(2);
+
+/// Consecutive failures opening the circuit of a peer.
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long an open circuit fails fast before letting a probe through.
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Circuit breakers and call policies of the peers this service talks to.
+pub static PEERS: Lazy<Peers> = Lazy::new(Peers::default);
+
+/// Timeout and retries of calls to one peer, configured with `<NAME>_TIMEOUT_MS` and
+/// `<NAME>_RETRIES` (e.g. `PROJECT_TIMEOUT_MS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+impl CallPolicy {
+    pub fn from_env(peer: &str) -> Self {
+        let var = |suffix: &str| {
+            std::env::var(format!("{}_{}", peer.to_uppercase(), suffix))
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        let default = Self::default();
+        Self {
+            timeout: var("TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.timeout),
+            retries: var("RETRIES")
+                .and_then(|retries| u32::try_from(retries).ok())
+                .unwrap_or(default.retries),
+        }
+    }
+
+    /// Full jitter backoff before retry number `attempt`, starting at 1.
+    pub fn backoff(attempt: u32) -> Duration {
+        let cap = BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(BACKOFF_MAX);
+        rand::thread_rng().gen_range(Duration::ZERO..=cap)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    /// Calls fail fast until the deadline, then a single probe is let through.
+    Open,
+    /// A probe is in flight; its outcome closes or reopens the circuit. A probe that never
+    /// reports back, e.g. because its caller was cancelled, is replaced after the deadline.
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn gauge(self) -> u8 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    pub policy: CallPolicy,
+    state: CircuitState,
+    failures: u32,
+    /// When the circuit opened or, while half open, when the probe went out.
+    opened_at: Option<Instant>,
+    requests: u64,
+    errors: u64,
+    retries: u64,
+    rejected: u64,
+}
+
+impl Peer {
+    fn new(policy: CallPolicy) -> Self {
+        Self {
+            policy,
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: None,
+            requests: 0,
+            errors: 0,
+            retries: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Whether a call may go out now.
+    fn acquire(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => {}
+            CircuitState::Open | CircuitState::HalfOpen
+                if self.opened_at.is_some_and(|at| now - at >= OPEN_DURATION) =>
+            {
+                self.state = CircuitState::HalfOpen;
+                self.opened_at = Some(now);
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                self.rejected += 1;
+                return false;
+            }
+        }
+        self.requests += 1;
+        true
+    }
+
+    fn succeeded(&mut self) {
+        self.state = CircuitState::Closed;
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.errors += 1;
+        self.failures += 1;
+        if self.state == CircuitState::HalfOpen || self.failures >= FAILURE_THRESHOLD {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+/// Name, type, help and value of a metric exposed per peer.
+type Metric = (&'static str, &'static str, &'static str, fn(&Peer) -> u64);
+
+const METRICS: [Metric; 5] = [
+    (
+        "peer_circuit_state",
+        "gauge",
+        "Circuit state: 0 closed, 1 open, 2 half open.",
+        |peer: &Peer| peer.state.gauge().into(),
+    ),
+    (
+        "peer_requests_total",
+        "counter",
+        "Calls sent to the peer.",
+        |peer: &Peer| peer.requests,
+    ),
+    (
+        "peer_errors_total",
+        "counter",
+        "Calls that failed with a timeout, a connection error or a 502/503/504.",
+        |peer: &Peer| peer.errors,
+    ),
+    (
+        "peer_retries_total",
+        "counter",
+        "Calls retried after a failure.",
+        |peer: &Peer| peer.retries,
+    ),
+    (
+        "peer_rejected_total",
+        "counter",
+        "Calls rejected by an open circuit.",
+        |peer: &Peer| peer.rejected,
+    ),
+];
+
+#[derive(Debug, Default)]
+pub struct Peers {
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl Peers {
+    fn with<R>(&self, peer: &str, f: impl FnOnce(&mut Peer) -> R) -> R {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers
+            .entry(peer.to_string())
+            .or_insert_with(|| Peer::new(CallPolicy::from_env(peer)));
+        f(peer)
+    }
+
+    pub fn policy(&self, peer: &str) -> CallPolicy {
+        self.with(peer, |peer| peer.policy)
+    }
+
+    pub fn state(&self, peer: &str) -> CircuitState {
+        self.with(peer, |peer| peer.state)
+    }
+
+    /// Reserves a call to `peer`, `false` when its circuit is open.
+    pub fn acquire(&self, peer: &str) -> bool {
+        self.with(peer, |peer| peer.acquire(Instant::now()))
+    }
+
+    pub fn succeeded(&self, peer: &str) {
+        self.with(peer, Peer::succeeded)
+    }
+
+    pub fn failed(&self, peer: &str) {
+        let opened = self.with(peer, |entry| {
+            let was_open = entry.state == CircuitState::Open;
+            entry.failed(Instant::now());
+            !was_open && entry.state == CircuitState::Open
+        });
+        if opened {
+            tracing::warn!("circuit to {} opened", peer);
+        }
+    }
+
+    pub fn retried(&self, peer: &str) {
+        self.with(peer, |peer| peer.retries += 1)
+    }
+
+    /// Prometheus text exposition of the circuit state and call counters of every peer.
+    pub fn render(&self) -> String {
+        let peers = self.peers.lock().unwrap();
+        let mut names: Vec<&String> = peers.keys().collect();
+        names.sort();
+
+        let mut out = String::new();
+        for (name, kind, help, value) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for peer in &names {
+                let _ = writeln!(
+                    out,
+                    "{}{{peer=\"{}\"}} {}",
+                    name,
+                    peer,
+                    value(&peers[*peer])
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Name of the peer serving `url`: the registry entry with the same origin, or its host.
+pub fn peer_name(url: &reqwest::Url) -> String {
+    let origin = url.origin().ascii_serialization();
+    REGISTRY
+        .name_of(&origin)
+        .map(str::to_string)
+        .or_else(|| url.host_str().map(str::to_string))
+        .unwrap_or(origin)
+}
+
+/// Serves [`Peers::render`], mounted at `/metrics`.
+pub async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        PEERS.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut peer = Peer::new(CallPolicy::default());
+
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            assert!(peer.acquire(now));
+            peer.failed(now);
+        }
+        assert_eq!(peer.state, CircuitState::Closed);
+
+        peer.succeeded();
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(peer.acquire(now));
+            peer.failed(now);
+        }
+        assert_eq!(peer.state, CircuitState::Open);
+        assert!(!peer.acquire(now));
+        assert_eq!(peer.rejected, 1);
+    }
+
+    #[test]
+    fn probes_after_open_duration() {
+        let now = Instant::now();
+        let mut peer = Peer::new(CallPolicy::default());
+        for _ in 0..FAILURE_THRESHOLD {
+            peer.acquire(now);
+            peer.failed(now);
+        }
+
+        let later = now + OPEN_DURATION;
+        assert!(peer.acquire(later));
+        assert_eq!(peer.state, CircuitState::HalfOpen);
+        assert!(!peer.acquire(later));
+
+        // The probe was dropped without an outcome, so another one goes out.
+        let later = later + OPEN_DURATION;
+        assert!(peer.acquire(later));
+        assert!(!peer.acquire(later));
+
+        peer.failed(later);
+        assert_eq!(peer.state, CircuitState::Open);
+
+        let later = later + OPEN_DURATION;
+        assert!(peer.acquire(later));
+        peer.succeeded();
+        assert_eq!(peer.state, CircuitState::Closed);
+        assert!(peer.acquire(later));
+    }
+
+    #[test]
+    fn caps_backoff() {
+        for attempt in 1..10 {
+            assert!(CallPolicy::backoff(attempt) <= BACKOFF_MAX);
+        }
+        assert!(CallPolicy::backoff(1) <= BACKOFF_BASE);
+    }
+}
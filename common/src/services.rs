@@ -1,4 +1,120 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
 use once_cell::sync::Lazy;
 
+/// Base urls of the other services, see [`ServiceRegistry::from_env`].
+pub static REGISTRY: Lazy<ServiceRegistry> =
+    Lazy::new(|| ServiceRegistry::from_env().expect("Invalid service registry"));
+
 pub static AUTH: Lazy<String> =
-    Lazy::new(|| std::env::var("AUTH_URL").unwrap_or_else(|_| "http://localhost:8080".to_string()));
+    Lazy::new(|| REGISTRY.resolve("auth").expect("auth is a known service"));
+
+/// Services of the backend with the ports they listen on.
+const SERVICES: &[(&str, u16)] = &[
+    ("auth", 3001),
+    ("user", 3002),
+    ("project", 3003),
+    ("audit", 3004),
+];
+
+/// Adds `http://` to addresses configured without a scheme.
+fn normalize(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServiceRegistry {
+    services: HashMap<String, String>,
+}
+
+impl ServiceRegistry {
+    pub fn new(services: HashMap<String, String>) -> Self {
+        Self {
+            services: services
+                .into_iter()
+                .map(|(name, url)| (name, normalize(&url)))
+                .collect(),
+        }
+    }
+
+    /// Reads the JSON file at `SERVICES_CONFIG`, mapping service names to base urls like
+    /// `{"project": "https://project.internal:3003"}`.
+    ///
+    /// `<NAME>_URL` variables of the services in `SERVICES` (e.g. `AUTH_URL`) take
+    /// precedence over the file. Other `_URL` variables, like `JWKS_URL`, aren't services.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut services: HashMap<String, String> = match std::env::var("SERVICES_CONFIG") {
+            Ok(path) => {
+                let file =
+                    std::fs::read(&path).with_context(|| format!("Failed to read {}", path))?;
+                serde_json::from_slice(&file)?
+            }
+            Err(_) => HashMap::new(),
+        };
+
+        for (name, _) in SERVICES {
+            if let Ok(url) = std::env::var(format!("{}_URL", name.to_uppercase())) {
+                services.insert(name.to_string(), url);
+            }
+        }
+
+        Ok(Self::new(services))
+    }
+
+    /// Configured base url of `service`.
+    pub fn get(&self, service: &str) -> Option<&str> {
+        self.services.get(service).map(String::as_str)
+    }
+
+    /// Service configured with the base url `origin`.
+    pub fn name_of(&self, origin: &str) -> Option<&str> {
+        self.services
+            .iter()
+            .find(|(_, url)| url.as_str() == origin)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Base url of `service`, falling back to its DNS name and port as in docker-compose.
+    pub fn resolve(&self, service: &str) -> anyhow::Result<String> {
+        if let Some(url) = self.get(service) {
+            return Ok(url.to_string());
+        }
+        match SERVICES.iter().find(|(name, _)| *name == service) {
+            Some((name, port)) => Ok(format!("http://{}:{}", name, port)),
+            None => Err(anyhow!(
+                "Service {} is unknown, add it to SERVICES_CONFIG",
+                service
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_configured_and_known_services() {
+        let registry = ServiceRegistry::new(HashMap::from([(
+            "project".to_string(),
+            "project.internal:8443/".to_string(),
+        )]));
+
+        assert_eq!(
+            registry.resolve("project").unwrap(),
+            "http://project.internal:8443"
+        );
+        assert_eq!(registry.resolve("audit").unwrap(), "http://audit:3004");
+        assert!(registry.resolve("jwks").is_err());
+        assert_eq!(
+            registry.name_of("http://project.internal:8443"),
+            Some("project")
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! `#[derive(Entity)]`, implementing `common::entity::Entity` for structs with named fields.
+//!
+//! ```ignore
+//! #[derive(Entity, Serialize, Deserialize)]
+//! #[entity(name = "login", public = "LoginPublic")]
+//! pub struct Login {
+//!     #[serde(rename = "_id")]
+//!     #[entity(owner)]
+//!     pub id: ObjectId,
+//!     pub login: Unique<String>,
+//!     pub password: Private<String>,
+//! }
+//! ```
+//!
+//! Container attributes:
+//!
+//! * `name = "..."` – `Entity::NAME` of a root entity, stored in its own collection;
+//! * `root = "Path"` – for structs nested in a root entity instead, implementing
+//!   `Entity<Path>` and sharing its name;
+//! * `public = "Ident"` – name of the generated public struct, `<Struct>Public` by default;
+//! * `counterpart = "path"` – implements `Entity::is_counterpart` with
+//!   `async fn(&Self, ObjectId, &Context) -> anyhow::Result<bool>`.
+//!
+//! Field attributes:
+//!
+//! * `owner` – adds the field to `Entity::OWNER_FIELDS`.
+//!
+//! Stored keys follow `#[serde(rename = "...")]` on the fields. Container level
+//! `#[serde(rename_all = "...")]` is rejected.
+//!
+//! The public struct holds the `PublicEntity` of every field except `Private` ones, under
+//! the same serde names as the stored struct. Hooks run on each field in declaration order,
+//! with `MutationContext::current_field` set to the dotted path of the field in the stored
+//! document, until one of them aborts.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    meta::ParseNestedMeta, parse_macro_input, token, Attribute, Data, DeriveInput, Expr, Fields,
+    Ident, LitStr, Path, Token, Type,
+};
+
+#[proc_macro_derive(Entity, attributes(entity))]
+pub fn derive_entity(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct Container {
+    name: Option<LitStr>,
+    root: Option<Path>,
+    public: Option<Ident>,
+    counterpart: Option<Path>,
+}
+
+impl Container {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut container = Container::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("entity")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    container.name = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("root") {
+                    container.root = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                } else if meta.path.is_ident("public") {
+                    container.public = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                } else if meta.path.is_ident("counterpart") {
+                    container.counterpart = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                } else {
+                    return Err(meta.error("expected `name`, `root`, `public` or `counterpart`"));
+                }
+                Ok(())
+            })?;
+        }
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                // Stored keys are derived from the field names, see `Field::key`.
+                if meta.path.is_ident("rename_all") {
+                    return Err(meta.error(
+                        "Entity doesn't support `rename_all`, rename the fields one by one",
+                    ));
+                }
+                skip_meta(&meta)
+            })?;
+        }
+        Ok(container)
+    }
+}
+
+/// Consumes a serde item this derive has no use for: a flag, `key = value` or `key(...)`.
+fn skip_meta(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(token::Paren) {
+        meta.parse_nested_meta(|nested| skip_meta(&nested))?;
+    }
+    Ok(())
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    /// Name of the field in the stored document.
+    key: String,
+    owner: bool,
+}
+
+impl Field {
+    fn parse(field: &syn::Field) -> syn::Result<Self> {
+        let ident = field.ident.clone().expect("named field");
+        let mut key = ident.to_string();
+        let mut owner = false;
+
+        for attr in &field.attrs {
+            if attr.path().is_ident("entity") {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("owner") {
+                        owner = true;
+                        Ok(())
+                    } else {
+                        Err(meta.error("expected `owner`"))
+                    }
+                })?;
+            } else if attr.path().is_ident("serde") {
+                // Only `rename` matters here, everything else is serde's business.
+                attr.parse_nested_meta(|meta| {
+                    if !meta.path.is_ident("rename") {
+                        return skip_meta(&meta);
+                    }
+                    if meta.input.peek(Token![=]) {
+                        key = meta.value()?.parse::<LitStr>()?.value();
+                        return Ok(());
+                    }
+                    // `rename(serialize = "..", deserialize = "..")`: documents are stored
+                    // under the serialized name.
+                    meta.parse_nested_meta(|nested| {
+                        if nested.path.is_ident("serialize") {
+                            key = nested.value()?.parse::<LitStr>()?.value();
+                            Ok(())
+                        } else {
+                            skip_meta(&nested)
+                        }
+                    })
+                })?;
+            }
+        }
+
+        Ok(Self {
+            ident,
+            ty: field.ty.clone(),
+            key,
+            owner,
+        })
+    }
+
+    /// `Private` fields are left out of the public struct.
+    fn is_public(&self) -> bool {
+        match &self.ty {
+            Type::Path(path) => !matches!(
+                path.path.segments.last(),
+                Some(segment) if segment.ident == "Private"
+            ),
+            _ => true,
+        }
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let container = Container::parse(&input.attrs)?;
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Entity can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Entity can only be derived for structs with named fields",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "Entity can't be derived for generic structs",
+        ));
+    }
+    let fields = named
+        .named
+        .iter()
+        .map(Field::parse)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let ident = &input.ident;
+    let vis = &input.vis;
+    let (root, name) = match (&container.name, &container.root) {
+        (Some(name), None) => (quote!(#ident), quote!(#name)),
+        (None, Some(root)) => (
+            quote!(#root),
+            quote!(<#root as ::common::entity::Entity<#root>>::NAME),
+        ),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "expected either #[entity(name = \"...\")] or #[entity(root = \"...\")]",
+            ))
+        }
+    };
+    let public = container
+        .public
+        .unwrap_or_else(|| format_ident!("{}Public", ident));
+    let entity = quote!(::common::entity::Entity<#root>);
+
+    let owners = fields
+        .iter()
+        .filter(|field| field.owner)
+        .map(|field| &field.key);
+
+    let public_fields = fields.iter().filter(|field| field.is_public());
+    let public_decls = public_fields.clone().map(|Field { ident, ty, key, .. }| {
+        // Public entities keep the field names of the stored ones, like `_id`.
+        let rename = (ident != key).then(|| quote!(#[serde(rename = #key)]));
+        quote!(#rename pub #ident: <#ty as #entity>::PublicEntity)
+    });
+    let public_values = public_fields.map(|Field { ident, ty, .. }| {
+        quote!(#ident: <#ty as #entity>::to_public(&self.#ident, context))
+    });
+
+    let hook = |hook: Ident| {
+        let entity = &entity;
+        fields.iter().map(move |Field { ident, ty, key, .. }| {
+            quote! {
+                context.current_field = Some(::common::entity::field_path(parent.as_deref(), #key));
+                result = result.and(<#ty as #entity>::#hook(&self.#ident, context, method).await?);
+                if let ::common::entity::HookResult::Abort(_) = result {
+                    context.current_field = parent;
+                    return Ok(result);
+                }
+            }
+        })
+    };
+    let before = hook(format_ident!("before_execution"));
+    let after = hook(format_ident!("after_execution"));
+
+    let counterpart = container.counterpart.map(|counterpart| {
+        quote! {
+            async fn is_counterpart(
+                &self,
+                user: ::common::__private::bson::oid::ObjectId,
+                context: &::common::context::Context,
+            ) -> ::common::__private::anyhow::Result<bool> {
+                #counterpart(self, user, context).await
+            }
+        }
+    });
+
+    let unique = fields.iter().map(|Field { ty, key, .. }| {
+        quote! {
+            <#ty as #entity>::unique_fields(
+                Some(&::common::entity::field_path(path, #key)),
+                fields,
+            );
+        }
+    });
+
+    Ok(quote! {
+        #[derive(Debug, ::common::__private::serde::Serialize)]
+        #[serde(crate = "::common::__private::serde")]
+        #vis struct #public {
+            #(#public_decls,)*
+        }
+
+        #[::common::__private::async_trait]
+        impl #entity for #ident {
+            type PublicEntity = #public;
+
+            const NAME: &'static str = #name;
+
+            const OWNER_FIELDS: &'static [&'static str] = &[#(#owners),*];
+
+            fn unique_fields(path: Option<&str>, fields: &mut Vec<String>) {
+                #(#unique)*
+            }
+
+            fn to_public(
+                &self,
+                context: &mut ::common::context::MutationContext,
+            ) -> Self::PublicEntity {
+                #public {
+                    #(#public_values,)*
+                }
+            }
+
+            async fn before_execution(
+                &self,
+                context: &mut ::common::context::MutationContext,
+                method: ::common::repository::Method,
+            ) -> ::common::__private::anyhow::Result<::common::entity::HookResult> {
+                let parent = context.current_field.take();
+                #[allow(unused_mut)]
+                let mut result = ::common::entity::HookResult::Continue;
+                #(#before)*
+                context.current_field = parent;
+                Ok(result)
+            }
+
+            async fn after_execution(
+                &self,
+                context: &mut ::common::context::MutationContext,
+                method: ::common::repository::Method,
+            ) -> ::common::__private::anyhow::Result<::common::entity::HookResult> {
+                let parent = context.current_field.take();
+                #[allow(unused_mut)]
+                let mut result = ::common::entity::HookResult::Continue;
+                #(#after)*
+                context.current_field = parent;
+                Ok(result)
+            }
+
+            #counterpart
+        }
+    })
+}
@@ -0,0 +1,3 @@
+#!/bin/bash
+cp /data/binaries/project_binary .
+./project_binary
\ No newline at end of file
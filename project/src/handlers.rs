@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use axum::{extract::Path, Json};
+use chrono::Utc;
+use common::{
+    auth::policy::is_owner,
+    context::{Context, ContextExtractor, MutationContext},
+    entity::{
+        audit_request::Party,
+        project::{Project, ProjectPublic, ProjectStatus, PublishOptions},
+        status::Lifecycle,
+        Entity,
+    },
+    error::{ServiceError, ServiceResponse},
+    repository::{Page, Query, ReadRepositoryTrait, RepositoryTrait},
+};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewProject {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub scope: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub prise_from: i64,
+    pub prise_to: i64,
+    #[serde(default)]
+    pub ready_to_wait: bool,
+}
+
+/// Published projects having any of `tags`, whose price window overlaps
+/// `price_from..=price_to`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Search {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub price_from: Option<i64>,
+    pub price_to: Option<i64>,
+    pub limit: Option<i64>,
+    pub after: Option<ObjectId>,
+}
+
+impl Search {
+    fn filter(&self) -> anyhow::Result<Document> {
+        let mut clauses = vec![doc! { "status": to_bson(&ProjectStatus::Published)? }];
+        if !self.tags.is_empty() {
+            clauses.push(doc! { "tags": { "$in": &self.tags } });
+        }
+        if let Some(price_to) = self.price_to {
+            clauses.push(doc! { "publish_options.prise_from": { "$lte": price_to } });
+        }
+        if let Some(price_from) = self.price_from {
+            clauses.push(doc! { "publish_options.prise_to": { "$gte": price_from } });
+        }
+        Ok(doc! { "$and": clauses })
+    }
+}
+
+fn check_prices(prise_from: i64, prise_to: i64) -> Result<(), ServiceError> {
+    if prise_from < 0 || prise_from > prise_to {
+        return Err(ServiceError::BadRequest(
+            "Price window must be a non-negative range".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn public(project: &Project, context: &Context) -> ProjectPublic {
+    project.to_public(&mut MutationContext::new(context))
+}
+
+/// Creates an unpublished project of the customer signed in.
+pub async fn create_draft(
+    ContextExtractor(context): ContextExtractor,
+    Json(new_project): Json<NewProject>,
+) -> ServiceResponse<ProjectPublic> {
+    let customer_id = context.caller()?;
+    if new_project.name.trim().is_empty() {
+        return Err(ServiceError::BadRequest(
+            "Name must not be empty".to_string(),
+        ));
+    }
+    check_prices(new_project.prise_from, new_project.prise_to)?;
+
+    let project = Project {
+        id: ObjectId::new(),
+        customer_id,
+        name: new_project.name,
+        description: new_project.description,
+        scope: new_project.scope,
+        tags: new_project.tags,
+        publish_options: PublishOptions {
+            publish: false,
+            prise_from: new_project.prise_from,
+            prise_to: new_project.prise_to,
+            ready_to_wait: new_project.ready_to_wait,
+        },
+        status: ProjectStatus::Draft,
+        last_modified: Utc::now().timestamp(),
+    };
+    context
+        .repository::<Project>()?
+        .insert(&project, &context)
+        .await?;
+
+    Ok(Json(public(&project, &context)))
+}
+
+/// Makes the project visible in `search`.
+pub async fn publish(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+) -> ServiceResponse<ProjectPublic> {
+    set_status(&id, ProjectStatus::Published, &context).await
+}
+
+/// Hides the project from `search` again.
+pub async fn unpublish(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+) -> ServiceResponse<ProjectPublic> {
+    set_status(&id, ProjectStatus::Draft, &context).await
+}
+
+/// Withdraws the project for good.
+pub async fn close(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+) -> ServiceResponse<ProjectPublic> {
+    set_status(&id, ProjectStatus::Closed, &context).await
+}
+
+async fn set_status(
+    id: &str,
+    status: ProjectStatus,
+    context: &Context,
+) -> ServiceResponse<ProjectPublic> {
+    let id = ObjectId::from_str(id)?;
+    let repository = context.repository::<Project>()?;
+
+    let Some(mut project) = repository.find(&id, context).await? else {
+        return Err(ServiceError::NotFound("Project not found".to_string()));
+    };
+    // Unlike the generated routes, admins can't act in the name of a customer.
+    if !is_owner(&project, context.1.end_user())? {
+        return Err(ServiceError::Forbidden(
+            "Only the customer of the project can change its status".to_string(),
+        ));
+    }
+    project.status.check_transition(status, Party::Customer)?;
+    check_prices(
+        project.publish_options.prise_from,
+        project.publish_options.prise_to,
+    )?;
+
+    // Only written while the status is still the one checked, so concurrent changes can't
+    // skip a transition.
+    let condition = doc! { "status": to_bson(&project.status)? };
+    project.publish_options.publish = status == ProjectStatus::Published;
+    project.status = status;
+    project.last_modified = Utc::now().timestamp();
+    let Some(project) = repository
+        .update_if(id, condition, &project, context)
+        .await?
+    else {
+        return Err(ServiceError::Conflict(
+            "The project changed meanwhile, reload it".to_string(),
+        ));
+    };
+
+    Ok(Json(public(&project, context)))
+}
+
+/// Lists the published projects of all customers, open to anyone.
+pub async fn search(
+    ContextExtractor(context): ContextExtractor,
+    Json(search): Json<Search>,
+) -> ServiceResponse<Page<ProjectPublic>> {
+    let query = Query {
+        filter: search.filter()?,
+        limit: search.limit,
+        after: search.after,
+        ..Default::default()
+    };
+    // Published projects aren't scoped to their customer.
+    let page = context
+        .repository::<Project>()?
+        .find_many(query, &context.privileged())
+        .await?;
+
+    Ok(Json(Page {
+        items: page
+            .items
+            .iter()
+            .map(|project| public(project, &context))
+            .collect(),
+        total: page.total,
+        next: page.next,
+    }))
+}
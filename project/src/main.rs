@@ -0,0 +1,41 @@
+use std::{sync::Arc, env, net::SocketAddr};
+
+use axum::{routing::{get, post}, Router};
+use common::{auth::keys, context::ServiceState, entity::project::Project, resilience, repository::{Repository, memory::InMemoryRepository, mongo::MongoRepository, http_repository::Registrable}};
+use project::handlers;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+    .with_max_level(tracing::Level::DEBUG)
+    .init();
+
+    let mut state = ServiceState::new("project".to_string());
+    match env::var("MONGOURI") {
+        Ok(mongo_uri) => state.insert(Repository(Arc::new(MongoRepository::<Project>::new(&mongo_uri, "project", "project").await))),
+        Err(_) => {
+            tracing::warn!("MONGOURI is not set, projects are kept in memory");
+            state.insert(Repository(Arc::new(InMemoryRepository::<Project>::new())));
+        }
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3003));
+
+    let router = Router::new()
+        .register::<Project>()
+        .route("/api/project/draft", post(handlers::create_draft))
+        .route("/api/project/search", post(handlers::search))
+        .route("/api/project/:id/publish", post(handlers::publish))
+        .route("/api/project/:id/unpublish", post(handlers::unpublish))
+        .route("/api/project/:id/close", post(handlers::close))
+        .route("/api/project/jwks", get(keys::jwks))
+        .route("/metrics", get(resilience::metrics))
+        .with_state(Arc::new(state));
+
+    tracing::info!("routs {:?}", router);
+
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await
+        .unwrap();
+}
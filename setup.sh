@@ -1,2 +1,4 @@
 #!/bin/bash
-cp /usr/src/audit_backend/target/release/auth /data/binaries/auth_binary
+for service in auth user project audit; do
+    cp /usr/src/audit_backend/target/release/$service /data/binaries/${service}_binary
+done
@@ -0,0 +1,3 @@
+#!/bin/bash
+cp /data/binaries/user_binary .
+./user_binary
\ No newline at end of file
@@ -0,0 +1,136 @@
+use std::str::FromStr;
+
+use axum::{extract::Path, Json};
+use common::{
+    auth::policy::viewer,
+    context::{Context, ContextExtractor, MutationContext},
+    entity::{
+        auditor::Auditor,
+        customer::Customer,
+        user::{PublicUser, User, ROLES},
+        Entity,
+    },
+    error::{ServiceError, ServiceResponse},
+    repository::{ReadRepositoryTrait, RepositoryTrait},
+};
+use mongodb::bson::{doc, oid::ObjectId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewUser {
+    pub email: String,
+    pub name: String,
+    pub current_role: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleChange {
+    pub role: String,
+}
+
+fn check_role(role: &str) -> Result<(), ServiceError> {
+    if !ROLES.contains(&role) {
+        return Err(ServiceError::BadRequest(format!(
+            "Role must be one of {}",
+            ROLES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// `entity` as the caller is allowed to see it.
+async fn public<T>(entity: T, context: &Context) -> anyhow::Result<T::PublicEntity>
+where
+    T: Entity<T> + Serialize + Sync,
+{
+    let mut mutation = MutationContext::new(context);
+    mutation.viewer = viewer(&entity, context).await?;
+    Ok(entity.to_public(&mut mutation))
+}
+
+/// Returns the user signed in.
+pub async fn me(ContextExtractor(context): ContextExtractor) -> ServiceResponse<PublicUser> {
+    let id = context.caller()?;
+    let Some(user) = context.repository::<User>()?.find(&id, &context).await? else {
+        return Err(ServiceError::NotFound("User not found".to_string()));
+    };
+
+    Ok(Json(public(user, &context).await?))
+}
+
+/// Creates the user signed in, under the id of their login.
+pub async fn create_me(
+    ContextExtractor(context): ContextExtractor,
+    Json(new_user): Json<NewUser>,
+) -> ServiceResponse<PublicUser> {
+    let id = context.caller()?;
+    check_role(&new_user.current_role)?;
+
+    let user = User {
+        id,
+        email: new_user.email,
+        name: new_user.name,
+        current_role: new_user.current_role,
+    };
+    context
+        .repository::<User>()?
+        .insert(&user, &context)
+        .await?;
+
+    Ok(Json(public(user, &context).await?))
+}
+
+/// Switches the user signed in between their customer and auditor profiles.
+pub async fn switch_role(
+    ContextExtractor(context): ContextExtractor,
+    Json(change): Json<RoleChange>,
+) -> ServiceResponse<PublicUser> {
+    let id = context.caller()?;
+    check_role(&change.role)?;
+    let has_profile = match change.role.as_str() {
+        "customer" => context
+            .repository::<Customer>()?
+            .find(&id, &context)
+            .await?
+            .is_some(),
+        _ => context
+            .repository::<Auditor>()?
+            .find(&id, &context)
+            .await?
+            .is_some(),
+    };
+    if !has_profile {
+        return Err(ServiceError::NotFound(format!(
+            "Create the {} profile before switching to it",
+            change.role
+        )));
+    }
+
+    let Some(user) = context
+        .repository::<User>()?
+        .patch(id, doc! { "current_role": change.role }, &context)
+        .await?
+    else {
+        return Err(ServiceError::NotFound("User not found".to_string()));
+    };
+
+    Ok(Json(public(user, &context).await?))
+}
+
+/// Profile of any user, with its private contacts shown to the counterparts of its audits.
+pub async fn profile<T>(
+    id: Path<String>,
+    ContextExtractor(context): ContextExtractor,
+) -> ServiceResponse<T::PublicEntity>
+where
+    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send + 'static,
+{
+    let id = ObjectId::from_str(&id)?;
+    // Profiles are public, unlike the documents behind the generated routes.
+    let privileged = context.privileged();
+    let Some(profile) = context.repository::<T>()?.find(&id, &privileged).await? else {
+        return Err(ServiceError::NotFound(format!("{} not found", T::NAME)));
+    };
+
+    Ok(Json(public(profile, &context).await?))
+}
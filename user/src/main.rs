@@ -1,3 +1,36 @@
-fn main() {
-    println!("Hello, world!");
+use std::{sync::Arc, net::SocketAddr};
+
+use axum::{routing::{get, post}, Router};
+use common::{auth::keys, context::ServiceState, entity::{auditor::Auditor, customer::Customer, user::User, Entity}, resilience, repository::http_repository::Registrable};
+use user::{handlers, repositories::insert_repositories};
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+    .with_max_level(tracing::Level::DEBUG)
+    .init();
+
+    let mut state = ServiceState::new("user".to_string());
+    insert_repositories(&mut state).await;
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3002));
+
+    let router = Router::new()
+        .register::<User>()
+        .register::<Customer>()
+        .register::<Auditor>()
+        .route("/api/user/me", get(handlers::me).post(handlers::create_me))
+        .route("/api/user/me/role", post(handlers::switch_role))
+        .route(&format!("/api/{}/profile/:id", Customer::NAME), get(handlers::profile::<Customer>))
+        .route(&format!("/api/{}/profile/:id", Auditor::NAME), get(handlers::profile::<Auditor>))
+        .route("/api/user/jwks", get(keys::jwks))
+        .route("/metrics", get(resilience::metrics))
+        .with_state(Arc::new(state));
+
+    tracing::info!("routs {:?}", router);
+
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await
+        .unwrap();
 }
@@ -0,0 +1,50 @@
+use std::{env, sync::Arc};
+
+use common::{
+    context::ServiceState,
+    entity::{
+        audit::Audit, audit_request::AuditRequest, auditor::Auditor, customer::Customer,
+        user::User, Entity,
+    },
+    repository::{
+        http_repository::HttpRepositoryClient, memory::InMemoryRepository, mongo::MongoRepository,
+        Repository,
+    },
+    services::REGISTRY,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Adds the repositories of users and their profiles, stored in MongoDB at `MONGOURI`
+/// or, without it, in memory.
+pub async fn insert_repositories(state: &mut ServiceState) {
+    let mongo_uri = env::var("MONGOURI").ok();
+    if mongo_uri.is_none() {
+        tracing::warn!("MONGOURI is not set, users are kept in memory");
+    }
+
+    insert_stored::<User>(state, mongo_uri.as_deref()).await;
+    insert_stored::<Customer>(state, mongo_uri.as_deref()).await;
+    insert_stored::<Auditor>(state, mongo_uri.as_deref()).await;
+
+    // Audits decide who sees the private contacts of a profile, see `are_counterparts`.
+    if let Some(audit) = REGISTRY.get("audit") {
+        state.insert(Repository(Arc::new(
+            HttpRepositoryClient::<AuditRequest>::new(audit),
+        )));
+        state.insert(Repository(Arc::new(HttpRepositoryClient::<Audit>::new(
+            audit,
+        ))));
+    }
+}
+
+async fn insert_stored<T>(state: &mut ServiceState, mongo_uri: Option<&str>)
+where
+    T: Entity<T> + Serialize + DeserializeOwned + Sync + Send + Unpin + 'static,
+{
+    match mongo_uri {
+        Some(mongo_uri) => state.insert(Repository(Arc::new(
+            MongoRepository::<T>::new(mongo_uri, "user", T::NAME).await,
+        ))),
+        None => state.insert(Repository(Arc::new(InMemoryRepository::<T>::new()))),
+    }
+}